@@ -1,3 +1,6 @@
+use crate::output::OutputFormat;
+use crate::select::parse_at;
+use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
 
 /// command to execute
@@ -24,20 +27,60 @@ pub enum Command {
         #[clap(short, long, env = "CONFIG_FILE")]
         config: String,
     },
+    /// list backup files of the realm, newest first
+    List {
+        /// name of the realm. recommended name format are (project)-(typeofdb)-(db)
+        #[clap(short, long)]
+        name: String,
+        /// output format
+        #[clap(short, long, value_enum, default_value_t)]
+        format: OutputFormat,
+        /// realms configuration TOML file path
+        #[clap(short, long, env = "CONFIG_FILE")]
+        config: String,
+    },
+    /// delete backup files outside of the realm lifetime
+    Prune {
+        /// name of the realm, all realms are pruned if empty
+        #[clap(short, long, default_value = "")]
+        name: String,
+        /// only print what would be kept and deleted
+        #[clap(long)]
+        dry_run: bool,
+        /// realms configuration TOML file path
+        #[clap(short, long, env = "CONFIG_FILE")]
+        config: String,
+    },
     /// send backup file to remote archive
     Push {
         /// name of the realm. recommended name format are (project)-(typeofdb)-(db)
         #[clap(short, long)]
         name: String,
         /// file name to be sent to archive
-        #[clap(short, long)]
-        file: String,
-        /// remove the original file if successfully uploaded
+        #[clap(
+            short,
+            long,
+            required_unless_present_any = ["stdin", "exec"],
+            conflicts_with_all = ["stdin", "exec"],
+            requires = "exchange_dir"
+        )]
+        file: Option<String>,
+        /// stream the backup from standard input
+        #[clap(long, requires = "key_name", conflicts_with = "exec")]
+        stdin: bool,
+        /// run the shell command and stream its standard output, e.g. "pg_dump mydb".
+        /// the upload fails if the command exits with error
+        #[clap(long, requires = "key_name")]
+        exec: Option<String>,
+        /// file name of the streamed backup in the realm
         #[clap(long)]
+        key_name: Option<String>,
+        /// remove the original file if successfully uploaded
+        #[clap(long, requires = "file")]
         clean: bool,
         /// exchange dir
         #[clap(short, long, env = "EXCHANGE_DIR")]
-        exchange_dir: String,
+        exchange_dir: Option<String>,
         /// realms configuration TOML file path
         #[clap(short, long, env = "CONFIG_FILE")]
         config: String,
@@ -47,9 +90,48 @@ pub enum Command {
         /// name of the realm. recommended name format are (project)-(typeofdb)-(db)
         #[clap(short, long)]
         name: String,
+        /// exact key of the backup to pull, instead of the latest one
+        #[clap(short, long, conflicts_with_all = ["at", "nth"])]
+        key: Option<String>,
+        /// pull the newest backup made at or before the time, in UTC:
+        /// RFC 3339, "YYYY-MM-DD HH:MM:SS" or "YYYY-MM-DD" (the end of the day)
+        #[clap(long, value_parser = parse_at, conflicts_with = "nth")]
+        at: Option<DateTime<Utc>>,
+        /// pull the Nth most recent backup, 1 is the latest
+        #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+        nth: Option<u64>,
+        /// stream the backup to standard output instead of the exchange dir
+        #[clap(long, conflicts_with = "exec")]
+        stdout: bool,
+        /// run the shell command and stream the backup into its standard input,
+        /// e.g. "psql mydb". fails if the command exits with error
+        #[clap(long)]
+        exec: Option<String>,
         /// exchange dir
-        #[clap(short, long, env = "EXCHANGE_DIR")]
-        exchange_dir: String,
+        #[clap(short, long, env = "EXCHANGE_DIR", required_unless_present_any = ["stdout", "exec"])]
+        exchange_dir: Option<String>,
+        /// realms configuration TOML file path
+        #[clap(short, long, env = "CONFIG_FILE")]
+        config: String,
+    },
+    /// realms configuration
+    Config {
+        #[clap(subcommand)]
+        cmd: ConfigCommand,
+    },
+}
+
+/// command on the realms configuration
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigCommand {
+    /// report every problem of the realms, fails if there are any
+    Check {
+        /// connect to the storage of each realm and list its files
+        #[clap(long)]
+        connect: bool,
+        /// also upload and delete the probe file, to check write permissions
+        #[clap(long, requires = "connect")]
+        write: bool,
         /// realms configuration TOML file path
         #[clap(short, long, env = "CONFIG_FILE")]
         config: String,
@@ -0,0 +1,479 @@
+use crate::s3::S3Object;
+use crate::storage::{ByteReader, StorageBackend};
+use anyhow::Context;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::TryStreamExt;
+use hmac::{Hmac, Mac};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH, LAST_MODIFIED};
+use reqwest::{Method, StatusCode, Url};
+use sha2::Sha256;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::StreamReader;
+use tracing::*;
+
+/// version of Blob service REST API
+const API_VERSION: &str = "2021-08-06";
+/// size of the block uploaded in one request
+const BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+/// how requests to the storage account are authorized
+#[derive(Clone)]
+pub enum AzureAuth {
+    /// storage account access key, base64 decoded
+    SharedKey(Vec<u8>),
+    /// shared access signature query string
+    Sas(String),
+}
+
+/// AzureContainer keeps realm files as block blobs in Azure Blob Storage container
+#[derive(Clone)]
+pub struct AzureContainer {
+    client: reqwest::Client,
+    auth: AzureAuth,
+    pub account: String,
+    /// URL of the container
+    pub url: Url,
+}
+
+impl std::fmt::Debug for AzureContainer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AzureContainer")
+            .field("url", &self.url.as_str())
+            .finish()
+    }
+}
+
+impl AzureContainer {
+    /// creates new container object. `endpoint` is the blob service URL,
+    /// `https://{account}.blob.core.windows.net` if not given
+    pub fn new(
+        account: &str,
+        access_key: Option<&str>,
+        sas_token: Option<&str>,
+        container: &str,
+        endpoint: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        tracing::debug!("accessing azure container {} of {}", container, account);
+        let auth = match (access_key, sas_token) {
+            (Some(key), _) => {
+                AzureAuth::SharedKey(BASE64.decode(key).context("invalid azure access key")?)
+            }
+            (None, Some(sas)) => AzureAuth::Sas(sas.trim_start_matches('?').to_string()),
+            (None, None) => anyhow::bail!("either access_key or sas_token is required"),
+        };
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://{}.blob.core.windows.net", account),
+        };
+        let url =
+            Url::parse(&format!("{}/{}", endpoint, container)).context("invalid azure endpoint")?;
+        let client = reqwest::Client::builder()
+            .build()
+            .context("Failed to create HTTP client")?;
+        Ok(Self {
+            client,
+            auth,
+            account: account.to_string(),
+            url,
+        })
+    }
+
+    /// URL of the blob with the given name
+    fn url_of(&self, blob: &str) -> Url {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .expect("azure url is a base")
+            .extend(blob.split('/'));
+        url
+    }
+
+    /// signs and sends the request
+    async fn send(
+        &self,
+        method: Method,
+        mut url: Url,
+        body: Option<Vec<u8>>,
+    ) -> anyhow::Result<reqwest::Response> {
+        if let AzureAuth::Sas(sas) = &self.auth {
+            let query = match url.query() {
+                Some(q) => format!("{}&{}", q, sas),
+                None => sas.clone(),
+            };
+            url.set_query(Some(&query));
+        }
+        let mut req = self.client.request(method, url);
+        let length = body.as_ref().map(|b| b.len()).unwrap_or_default();
+        if let Some(body) = body {
+            req = req.header(CONTENT_LENGTH, length).body(body);
+        }
+        let mut req = req
+            .header("x-ms-date", http_date(chrono::Utc::now()))
+            .header("x-ms-version", API_VERSION)
+            .build()?;
+        if let AzureAuth::SharedKey(key) = &self.auth {
+            let signature = sign(key, &string_to_sign(&self.account, &req, length));
+            let value = format!("SharedKey {}:{}", self.account, signature);
+            req.headers_mut().insert(AUTHORIZATION, value.parse()?);
+        }
+        let res = self.client.execute(req).await?;
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for AzureContainer {
+    #[instrument(ret, level = "info")]
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<S3Object>> {
+        let mut out = vec![];
+        let mut marker = String::new();
+        loop {
+            let mut url = self.url.clone();
+            url.query_pairs_mut()
+                .append_pair("restype", "container")
+                .append_pair("comp", "list")
+                .append_pair("prefix", prefix);
+            if !marker.is_empty() {
+                url.query_pairs_mut().append_pair("marker", &marker);
+            }
+            let body = self
+                .send(Method::GET, url, None)
+                .await?
+                .error_for_status()
+                .context("failed to list blobs")?
+                .text()
+                .await?;
+            let (blobs, next) = parse_blob_list(&body)?;
+            out.extend(blobs);
+            match next {
+                Some(next) => marker = next,
+                None => break,
+            }
+        }
+        // sort out by last modified
+        out.sort_by_key(|o| std::cmp::Reverse(o.last_modified));
+        Ok(out)
+    }
+
+    #[instrument(ret, level = "info", skip(body))]
+    async fn put(
+        &self,
+        filename: &str,
+        mut body: ByteReader,
+        _size: Option<u64>,
+    ) -> anyhow::Result<u64> {
+        // blob is uploaded as the list of blocks, which becomes visible only when committed
+        let mut block_ids = vec![];
+        let mut total = 0u64;
+        loop {
+            let mut block = Vec::with_capacity(BLOCK_SIZE);
+            while block.len() < BLOCK_SIZE {
+                let n = (&mut body)
+                    .take((BLOCK_SIZE - block.len()) as u64)
+                    .read_to_end(&mut block)
+                    .await
+                    .context("failed to read upload stream")?;
+                if n == 0 {
+                    break;
+                }
+            }
+            if block.is_empty() {
+                break;
+            }
+            let block_id = BASE64.encode(format!("{:08}", block_ids.len()));
+            let mut url = self.url_of(filename);
+            url.query_pairs_mut()
+                .append_pair("comp", "block")
+                .append_pair("blockid", &block_id);
+            total += block.len() as u64;
+            let is_last = block.len() < BLOCK_SIZE;
+            self.send(Method::PUT, url, Some(block))
+                .await?
+                .error_for_status()
+                .context("failed to put block")?;
+            block_ids.push(block_id);
+            if is_last {
+                break;
+            }
+        }
+
+        let mut block_list = r#"<?xml version="1.0" encoding="utf-8"?><BlockList>"#.to_string();
+        for id in &block_ids {
+            block_list.push_str(&format!("<Latest>{}</Latest>", id));
+        }
+        block_list.push_str("</BlockList>");
+        let mut url = self.url_of(filename);
+        url.query_pairs_mut().append_pair("comp", "blocklist");
+        self.send(Method::PUT, url, Some(block_list.into_bytes()))
+            .await?
+            .error_for_status()
+            .context("failed to put block list")?;
+        Ok(total)
+    }
+
+    #[instrument(level = "info")]
+    async fn get(&self, filename: &str) -> anyhow::Result<ByteReader> {
+        let res = self
+            .send(Method::GET, self.url_of(filename), None)
+            .await?
+            .error_for_status()
+            .context("failed to get blob")?;
+        let stream = res.bytes_stream().map_err(std::io::Error::other);
+        Ok(Box::pin(StreamReader::new(stream)))
+    }
+
+    #[instrument(ret, level = "warn")]
+    async fn delete(&self, filename: &str) -> anyhow::Result<()> {
+        self.send(Method::DELETE, self.url_of(filename), None)
+            .await?
+            .error_for_status()
+            .context("failed to delete blob")?;
+        Ok(())
+    }
+
+    #[instrument(ret, level = "info")]
+    async fn head(&self, filename: &str) -> anyhow::Result<Option<S3Object>> {
+        let res = self.send(Method::HEAD, self.url_of(filename), None).await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let res = res.error_for_status().context("failed to head blob")?;
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+        };
+        Ok(Some(S3Object {
+            key: filename.to_string(),
+            last_modified: chrono::DateTime::parse_from_rfc2822(header(LAST_MODIFIED))
+                .map(|d| d.into())
+                .unwrap_or_else(|_| chrono::Utc::now()),
+            size: header(CONTENT_LENGTH).parse().unwrap_or_default(),
+        }))
+    }
+}
+
+/// date in the format of HTTP headers
+fn http_date(date: chrono::DateTime<chrono::Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// string to sign for Shared Key authorization of Blob service
+fn string_to_sign(account: &str, req: &reqwest::Request, content_length: usize) -> String {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let content_length = match content_length {
+        0 => String::new(),
+        n => n.to_string(),
+    };
+    let mut out = vec![
+        req.method().as_str().to_string(),
+        header("content-encoding"),
+        header("content-language"),
+        content_length,
+        header("content-md5"),
+        header("content-type"),
+        String::new(), // Date, x-ms-date is used instead
+        header("if-modified-since"),
+        header("if-match"),
+        header("if-none-match"),
+        header("if-unmodified-since"),
+        header("range"),
+    ];
+    let mut ms_headers: Vec<(String, String)> = req
+        .headers()
+        .iter()
+        .filter(|(name, _)| name.as_str().starts_with("x-ms-"))
+        .map(|(name, value)| {
+            let value = value.to_str().unwrap_or_default().trim().to_string();
+            (name.as_str().to_string(), value)
+        })
+        .collect();
+    ms_headers.sort();
+    for (name, value) in ms_headers {
+        out.push(format!("{}:{}", name, value));
+    }
+    let mut resource = format!("/{}{}", account, req.url().path());
+    let mut params: Vec<(String, String)> = req
+        .url()
+        .query_pairs()
+        .map(|(k, v)| (k.to_lowercase(), v.to_string()))
+        .collect();
+    params.sort();
+    for (name, value) in params {
+        resource.push_str(&format!("\n{}:{}", name, value));
+    }
+    out.push(resource);
+    out.join("\n")
+}
+
+fn sign(key: &[u8], string_to_sign: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(string_to_sign.as_bytes());
+    BASE64.encode(mac.finalize().into_bytes())
+}
+
+/// parses the response of List Blobs request, returns blobs and the marker of the next page
+fn parse_blob_list(xml: &str) -> anyhow::Result<(Vec<S3Object>, Option<String>)> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let mut out = vec![];
+    let mut next_marker = None;
+    let mut blob: Option<S3Object> = None;
+    // name of the element which text is being read
+    let mut current = String::new();
+    loop {
+        match reader.read_event().context("invalid List Blobs response")? {
+            Event::Start(e) => {
+                current = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                if current == "Blob" {
+                    blob = Some(S3Object {
+                        key: String::new(),
+                        last_modified: chrono::Utc::now(),
+                        size: 0,
+                    });
+                }
+            }
+            Event::Text(e) => {
+                let text = e.unescape()?;
+                match (current.as_str(), blob.as_mut()) {
+                    ("Name", Some(blob)) => blob.key = text.to_string(),
+                    ("Last-Modified", Some(blob)) => {
+                        if let Ok(d) = chrono::DateTime::parse_from_rfc2822(&text) {
+                            blob.last_modified = d.into();
+                        }
+                    }
+                    ("Content-Length", Some(blob)) => blob.size = text.parse().unwrap_or_default(),
+                    ("NextMarker", None) => next_marker = Some(text.to_string()),
+                    _ => {}
+                }
+            }
+            Event::End(e) => {
+                if e.local_name().as_ref() == b"Blob" {
+                    out.extend(blob.take());
+                }
+                current.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok((out, next_marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// well-known account key of Azurite emulator
+    const AZURITE_KEY: &str =
+        "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+    #[test]
+    fn test_string_to_sign() {
+        let container = AzureContainer::new(
+            "devstoreaccount1",
+            Some(AZURITE_KEY),
+            None,
+            "backups",
+            Some("http://127.0.0.1:10000/devstoreaccount1"),
+        )
+        .unwrap();
+        let mut url = container.url_of("db/dump 1.sql");
+        url.query_pairs_mut()
+            .append_pair("comp", "block")
+            .append_pair("blockid", "MDAwMDAwMDA=");
+        let req = reqwest::Client::new()
+            .put(url)
+            .header("x-ms-version", API_VERSION)
+            .header("x-ms-date", "Wed, 03 Apr 2024 11:30:00 GMT")
+            .build()
+            .unwrap();
+        assert_eq!(
+            string_to_sign("devstoreaccount1", &req, 9),
+            "PUT\n\n\n9\n\n\n\n\n\n\n\n\n\
+             x-ms-date:Wed, 03 Apr 2024 11:30:00 GMT\n\
+             x-ms-version:2021-08-06\n\
+             /devstoreaccount1/devstoreaccount1/backups/db/dump%201.sql\n\
+             blockid:MDAwMDAwMDA=\n\
+             comp:block"
+        );
+        assert_eq!(sign(b"secret", "GET").len(), 44);
+    }
+
+    #[test]
+    fn test_parse_blob_list() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<EnumerationResults ServiceEndpoint="http://127.0.0.1:10000/devstoreaccount1" ContainerName="backups">
+  <Prefix>db/</Prefix>
+  <Blobs>
+    <Blob>
+      <Name>db/dump-1.sql</Name>
+      <Properties>
+        <Last-Modified>Wed, 03 Apr 2024 11:30:00 GMT</Last-Modified>
+        <Content-Length>1024</Content-Length>
+        <BlobType>BlockBlob</BlobType>
+      </Properties>
+    </Blob>
+    <Blob>
+      <Name>db/dump-2.sql</Name>
+      <Properties>
+        <Last-Modified>Thu, 04 Apr 2024 11:30:00 GMT</Last-Modified>
+        <Content-Length>2048</Content-Length>
+      </Properties>
+    </Blob>
+  </Blobs>
+  <NextMarker>2!80!ZGItZHVtcC0zLnNxbA--</NextMarker>
+</EnumerationResults>"#;
+        let (blobs, next) = parse_blob_list(xml).unwrap();
+        assert_eq!(blobs.len(), 2);
+        assert_eq!(blobs[0].key, "db/dump-1.sql");
+        assert_eq!(blobs[0].size, 1024);
+        assert_eq!(
+            blobs[1].last_modified.to_rfc3339(),
+            "2024-04-04T11:30:00+00:00"
+        );
+        assert_eq!(next.as_deref(), Some("2!80!ZGItZHVtcC0zLnNxbA--"));
+
+        let (blobs, next) =
+            parse_blob_list("<EnumerationResults><Blobs /><NextMarker /></EnumerationResults>")
+                .unwrap();
+        assert!(blobs.is_empty());
+        assert!(next.is_none());
+    }
+
+    /// runs against Azurite emulator, e.g.
+    /// `docker run -p 10000:10000 mcr.microsoft.com/azure-storage/azurite azurite-blob --blobHost 0.0.0.0`,
+    /// with the container "backups" created, and `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_azurite() {
+        let container = AzureContainer::new(
+            "devstoreaccount1",
+            Some(AZURITE_KEY),
+            None,
+            "backups",
+            Some("http://127.0.0.1:10000/devstoreaccount1"),
+        )
+        .unwrap();
+
+        let body: ByteReader = Box::pin(std::io::Cursor::new(b"select 1;".to_vec()));
+        assert_eq!(container.put("test/db-1.sql", body, None).await.unwrap(), 9);
+        let list = container.list("test/").await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].size, 9);
+        assert!(container.head("test/db-1.sql").await.unwrap().is_some());
+        assert!(container.head("test/db-0.sql").await.unwrap().is_none());
+        container.delete("test/db-1.sql").await.unwrap();
+        assert!(container.list("test/").await.unwrap().is_empty());
+    }
+}
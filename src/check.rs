@@ -0,0 +1,259 @@
+use crate::crypto::EncryptionConfig;
+use crate::realms::{Realm, RealmLifetime, RealmLocation};
+use crate::s3::RetryConfig;
+use crate::secrets;
+use crate::storage::partial_name;
+use anyhow::Context;
+use toml::Value;
+
+/// problem found in the realm config
+#[derive(Debug, PartialEq)]
+pub struct ConfigProblem {
+    pub realm: String,
+    /// field of the realm, empty if the problem is not bound to one
+    pub field: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.field.is_empty() {
+            true => write!(f, "[{}] {}", self.realm, self.message),
+            false => write!(f, "[{}] {}: {}", self.realm, self.field, self.message),
+        }
+    }
+}
+
+/// result of the config check
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+    /// number of the realms checked
+    pub realms: usize,
+    pub problems: Vec<ConfigProblem>,
+}
+
+/// checks every realm of the config file, so all the problems are reported at once.
+/// With `connect` the files of the realms are listed, and with `write` the probe file
+/// is uploaded and deleted too
+pub async fn check(file_path: &str, connect: bool, write: bool) -> anyhow::Result<ConfigReport> {
+    let contents = std::fs::read_to_string(file_path)
+        .with_context(|| format!("failed to read config {}", file_path))?;
+    check_str(&contents, connect, write).await
+}
+
+async fn check_str(contents: &str, connect: bool, write: bool) -> anyhow::Result<ConfigReport> {
+    let value: Value = toml::from_str(contents).context("invalid TOML")?;
+    let realms = match value.get("realms") {
+        Some(Value::Table(realms)) => realms.clone(),
+        Some(_) => anyhow::bail!("realms is expected to be a table"),
+        None => anyhow::bail!("missing [realms] table"),
+    };
+    let mut report = ConfigReport {
+        realms: realms.len(),
+        ..Default::default()
+    };
+    for (name, value) in realms {
+        for (field, message) in check_realm(value, connect, write).await {
+            report.problems.push(ConfigProblem {
+                realm: name.clone(),
+                field,
+                message,
+            });
+        }
+    }
+    Ok(report)
+}
+
+async fn check_realm(mut value: Value, connect: bool, write: bool) -> Vec<(String, String)> {
+    // misspelled keys are ignored by serde, so they are looked for before parsing
+    let mut problems = unknown_fields(&value);
+    if let Err(e) = secrets::resolve(&mut value) {
+        problems.push((String::new(), format!("{:#}", e)));
+        return problems;
+    }
+    let realm: Realm = match value.try_into() {
+        Ok(realm) => realm,
+        Err(e) => {
+            problems.push((String::new(), e.to_string().trim().to_string()));
+            return problems;
+        }
+    };
+    let mut found = realm.problems();
+    // connection is pointless with invalid settings
+    if connect && found.is_empty() && problems.is_empty() {
+        if let Err(problem) = check_connection(&realm, write).await {
+            found.push(problem);
+        }
+    }
+    problems.extend(found.into_iter().map(|(f, m)| (f.to_string(), m)));
+    problems
+}
+
+/// keys of the realm, which are not known for its transport.
+/// Nothing is reported for the unknown transport, serde fails on it anyway
+fn unknown_fields(value: &Value) -> Vec<(String, String)> {
+    let Some(table) = value.as_table() else {
+        return vec![];
+    };
+    let transport = table.get("transport").and_then(Value::as_str);
+    let Some(location) = transport.and_then(RealmLocation::fields) else {
+        return vec![];
+    };
+    let known = [Realm::FIELDS, RealmLifetime::FIELDS, &location].concat();
+    let mut out = vec![];
+    for (key, item) in table {
+        if !known.contains(&key.as_str()) {
+            out.push((key.clone(), "unknown field".to_string()));
+            continue;
+        }
+        let nested = match key.as_str() {
+            "encryption" => EncryptionConfig::FIELDS,
+            "retry" => RetryConfig::FIELDS,
+            _ => continue,
+        };
+        for nested_key in item.as_table().into_iter().flat_map(|t| t.keys()) {
+            if !nested.contains(&nested_key.as_str()) {
+                out.push((
+                    format!("{}.{}", key, nested_key),
+                    "unknown field".to_string(),
+                ));
+            }
+        }
+    }
+    out
+}
+
+/// lists the files of the realm, and uploads and deletes the probe file if `write` is set.
+/// The probe is named as an unfinished upload, so it is never taken for a backup
+async fn check_connection(realm: &Realm, write: bool) -> Result<(), (&'static str, String)> {
+    let storage = realm
+        .location
+        .backend()
+        .await
+        .map_err(|e| ("transport", format!("failed to connect: {:#}", e)))?;
+    storage
+        .list(&realm.prefix)
+        .await
+        .map_err(|e| ("prefix", format!("failed to list files: {:#}", e)))?;
+    if write {
+        let key = format!("{}{}", realm.prefix, partial_name("backup-server-check"));
+        let body = Box::pin(std::io::Cursor::new(b"check".to_vec()));
+        storage
+            .put(&key, body, Some(5))
+            .await
+            .map_err(|e| ("prefix", format!("failed to upload {}: {:#}", key, e)))?;
+        storage
+            .delete(&key)
+            .await
+            .map_err(|e| ("prefix", format!("failed to delete {}: {:#}", key, e)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check() {
+        let dir = std::env::temp_dir().join(format!("backup-server-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let contents = format!(
+            r#"
+[realms.db]
+transport = "Local"
+path = "{}"
+prefix = "db/"
+
+[realms.media]
+transport = "S3"
+bucket = " "
+region = "mars-1"
+credentials = "static"
+access_key = "AKID"
+max_files = 3
+min_files = 5
+compression_level = 3
+
+[realms.ftp]
+transport = "FTP"
+
+[realms.files]
+transport = "SFTP"
+host = "backup.example.com"
+user = "backup"
+path = "/backups"
+
+[realms.logs]
+transport = "Local"
+path = "${{BACKUP_TEST_MISSING}}"
+"#,
+            dir.display()
+        );
+        let report = check_str(&contents, true, true).await.unwrap();
+        let problems: Vec<String> = report.problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(report.realms, 5);
+        assert_eq!(problems.len(), 8, "{:#?}", problems);
+        assert_eq!(
+            problems[0],
+            "[files] key_file: either key_file or password is required"
+        );
+        assert!(problems[1].starts_with("[ftp] unknown variant `FTP`"));
+        assert_eq!(
+            problems[2..],
+            [
+                "[logs] missing environment variable BACKUP_TEST_MISSING in path",
+                "[media] credentials: static credentials require access_key and secret_access_key",
+                "[media] bucket: is empty",
+                "[media] region: invalid s3 region mars-1: Not a valid AWS region: mars-1",
+                "[media] min_files: is greater than max_files 3",
+                "[media] compression_level: is set without compression",
+            ]
+        );
+        // the probe file is deleted
+        assert_eq!(std::fs::read_dir(dir.join("db")).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        let report = check_str(&contents, true, false).await.unwrap();
+        assert!(report.problems[0]
+            .to_string()
+            .starts_with("[db] transport: failed to connect: local directory"));
+        assert!(check_str("[realms", false, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_fields() {
+        let contents = r#"
+[realms.media]
+transport = "S3"
+bucket = "media"
+region = "eu-west-1"
+max_file = 3
+part_size = 8
+min_files = "2"
+
+[realms.media.retry]
+max_attempt = 2
+
+[realms.db]
+transport = "Local"
+path = "/backups"
+max_files = 3
+known_hosts = "/etc/ssh/known_hosts"
+"#;
+        let report = check_str(contents, false, false).await.unwrap();
+        let problems: Vec<String> = report.problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems.len(), 5, "{:#?}", problems);
+        assert_eq!(
+            problems[..4],
+            [
+                "[db] known_hosts: unknown field",
+                "[media] max_file: unknown field",
+                "[media] part_size: unknown field",
+                "[media] retry.max_attempt: unknown field",
+            ]
+        );
+        // the type error is reported along with the misspelled keys
+        assert!(problems[4].starts_with("[media] invalid type: string \"2\""));
+    }
+}
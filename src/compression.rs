@@ -0,0 +1,101 @@
+use crate::storage::ByteReader;
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder};
+use async_compression::Level;
+use serde::Deserialize;
+use tokio::io::BufReader;
+
+/// compression of the realm files, applied before upload
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    /// extension of the compressed files, which are decompressed on pull
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Zstd => ".zst",
+            Self::Gzip => ".gz",
+        }
+    }
+
+    /// valid compression levels
+    pub fn levels(&self) -> std::ops::RangeInclusive<i32> {
+        match self {
+            // negative levels are the fast ones
+            Self::Zstd => -131072..=22,
+            Self::Gzip => 0..=9,
+        }
+    }
+
+    /// compression of the file by its key extension, with the key stripped of it
+    pub fn of_key(key: &str) -> Option<(Self, &str)> {
+        [Self::Zstd, Self::Gzip].into_iter().find_map(|c| {
+            key.strip_suffix(c.extension())
+                .map(|stripped| (c, stripped))
+        })
+    }
+
+    /// compresses the stream, with the default level of the algorithm if missing
+    pub fn compress(&self, body: ByteReader, level: Option<i32>) -> ByteReader {
+        let level = level.map_or(Level::Default, Level::Precise);
+        let body = BufReader::new(body);
+        match self {
+            Self::Zstd => Box::pin(ZstdEncoder::with_quality(body, level)),
+            Self::Gzip => Box::pin(GzipEncoder::with_quality(body, level)),
+        }
+    }
+
+    /// decompresses the stream
+    pub fn decompress(&self, body: ByteReader) -> ByteReader {
+        let body = BufReader::new(body);
+        match self {
+            Self::Zstd => Box::pin(ZstdDecoder::new(body)),
+            Self::Gzip => Box::pin(GzipDecoder::new(body)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let contents = "insert into t values (1);\n".repeat(1000);
+        for (compression, level) in [(Compression::Zstd, Some(19)), (Compression::Gzip, None)] {
+            let body: ByteReader = Box::pin(std::io::Cursor::new(contents.clone().into_bytes()));
+            let mut compressed = vec![];
+            compression
+                .compress(body, level)
+                .read_to_end(&mut compressed)
+                .await
+                .unwrap();
+            assert!(compressed.len() < contents.len() / 10);
+
+            let mut decompressed = String::new();
+            compression
+                .decompress(Box::pin(std::io::Cursor::new(compressed)))
+                .read_to_string(&mut decompressed)
+                .await
+                .unwrap();
+            assert_eq!(decompressed, contents);
+        }
+    }
+
+    #[test]
+    fn test_of_key() {
+        assert_eq!(
+            Compression::of_key("db/dump.sql.zst"),
+            Some((Compression::Zstd, "db/dump.sql"))
+        );
+        assert_eq!(
+            Compression::of_key("db/dump.sql.gz"),
+            Some((Compression::Gzip, "db/dump.sql"))
+        );
+        assert_eq!(Compression::of_key("db/dump.sql"), None);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::storage::{checked_reader, ByteReader};
+use age::secrecy::SecretString;
+use age::{scrypt, x25519, Decryptor, Encryptor, Identity, Recipient};
+use anyhow::Context;
+use futures::AsyncWriteExt;
+use serde::Deserialize;
+use std::str::FromStr;
+use tokio_util::compat::{
+    FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt,
+};
+
+/// extension of the encrypted files, which are decrypted on pull
+pub const EXTENSION: &str = ".age";
+
+/// client-side encryption of the realm files, in age format.
+/// Either the keys or the passphrase are used, each read from a file or an environment variable
+#[derive(Debug, Deserialize)]
+pub struct EncryptionConfig {
+    /// age public keys ("age1..."), the files are encrypted to
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    /// age identity file, to decrypt the files on pull. Its public keys are recipients too
+    #[serde(default)]
+    pub identity_file: Option<String>,
+    /// environment variable with age identity ("AGE-SECRET-KEY-1...")
+    #[serde(default)]
+    pub identity_env: Option<String>,
+    /// file with the passphrase, used instead of the keys
+    #[serde(default)]
+    pub passphrase_file: Option<String>,
+    /// environment variable with the passphrase, used instead of the keys
+    #[serde(default)]
+    pub passphrase_env: Option<String>,
+}
+
+enum Keys {
+    Passphrase(SecretString),
+    X25519 {
+        recipients: Vec<x25519::Recipient>,
+        identities: Vec<x25519::Identity>,
+    },
+}
+
+impl EncryptionConfig {
+    /// config keys of the encryption
+    pub const FIELDS: &'static [&'static str] = &[
+        "recipients",
+        "identity_file",
+        "identity_env",
+        "passphrase_file",
+        "passphrase_env",
+    ];
+
+    fn keys(&self) -> anyhow::Result<Keys> {
+        let passphrase = match (&self.passphrase_file, &self.passphrase_env) {
+            (Some(path), _) => Some(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read passphrase file {}", path))?
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string(),
+            ),
+            (_, Some(name)) => Some(
+                std::env::var(name)
+                    .with_context(|| format!("missing passphrase variable {}", name))?,
+            ),
+            _ => None,
+        };
+        let identities = match (&self.identity_file, &self.identity_env) {
+            (Some(path), _) => parse_identities(
+                &std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read identity file {}", path))?,
+            )
+            .with_context(|| format!("invalid identity file {}", path))?,
+            (_, Some(name)) => parse_identities(
+                &std::env::var(name)
+                    .with_context(|| format!("missing identity variable {}", name))?,
+            )
+            .with_context(|| format!("invalid identity in {}", name))?,
+            _ => vec![],
+        };
+        match passphrase {
+            Some(_) if !identities.is_empty() || !self.recipients.is_empty() => {
+                anyhow::bail!("encryption uses either the keys or the passphrase, not both")
+            }
+            Some(passphrase) if passphrase.is_empty() => anyhow::bail!("empty passphrase"),
+            Some(passphrase) => Ok(Keys::Passphrase(passphrase.into())),
+            None => {
+                let mut recipients = self
+                    .recipients
+                    .iter()
+                    .map(|r| {
+                        x25519::Recipient::from_str(r)
+                            .map_err(|e| anyhow::anyhow!("invalid recipient {}: {}", r, e))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                recipients.extend(identities.iter().map(|i| i.to_public()));
+                Ok(Keys::X25519 {
+                    recipients,
+                    identities,
+                })
+            }
+        }
+    }
+
+    /// checks that the keys can be read and the files can be encrypted
+    pub fn check(&self) -> anyhow::Result<()> {
+        match self.keys()? {
+            Keys::X25519 { recipients, .. } if recipients.is_empty() => {
+                anyhow::bail!("no recipients to encrypt to")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// encrypts the stream
+    pub async fn encrypt(&self, mut body: ByteReader) -> anyhow::Result<ByteReader> {
+        let encryptor = match self.keys()? {
+            Keys::Passphrase(passphrase) => Encryptor::with_user_passphrase(passphrase),
+            Keys::X25519 { recipients, .. } => {
+                if recipients.is_empty() {
+                    anyhow::bail!("no recipients to encrypt to");
+                }
+                Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn Recipient))?
+            }
+        };
+        // age encrypts what is written, so the stream is piped through it
+        let (reader, writer) = tokio::io::duplex(64 * 1024);
+        let task = tokio::spawn(async move {
+            let mut output = encryptor.wrap_async_output(writer.compat_write()).await?;
+            futures::io::copy(&mut (&mut body).compat(), &mut output).await?;
+            output.close().await
+        });
+        let check = async move { task.await.map_err(std::io::Error::other)? };
+        Ok(checked_reader(Box::pin(reader), check))
+    }
+
+    /// decrypts the stream
+    pub async fn decrypt(&self, body: ByteReader) -> anyhow::Result<ByteReader> {
+        let decryptor = Decryptor::new_async(body.compat())
+            .await
+            .context("invalid encrypted file")?;
+        let reader = match self.keys()? {
+            Keys::Passphrase(passphrase) => {
+                let identity = scrypt::Identity::new(passphrase);
+                decryptor.decrypt_async(std::iter::once(&identity as &dyn Identity))
+            }
+            Keys::X25519 { identities, .. } => {
+                if identities.is_empty() {
+                    anyhow::bail!("no identity to decrypt with");
+                }
+                decryptor.decrypt_async(identities.iter().map(|i| i as &dyn Identity))
+            }
+        }
+        .context("failed to decrypt")?;
+        Ok(Box::pin(reader.compat()))
+    }
+}
+
+/// identities of age key file, one per line, with "#" comments
+fn parse_identities(contents: &str) -> anyhow::Result<Vec<x25519::Identity>> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| x25519::Identity::from_str(line).map_err(|e| anyhow::anyhow!("{}", e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+    use tokio::io::AsyncReadExt;
+
+    async fn roundtrip(config: &EncryptionConfig) -> Vec<u8> {
+        let body: ByteReader = Box::pin(std::io::Cursor::new(b"select 1;".to_vec()));
+        let mut encrypted = vec![];
+        config
+            .encrypt(body)
+            .await
+            .unwrap()
+            .read_to_end(&mut encrypted)
+            .await
+            .unwrap();
+        assert!(!encrypted.windows(9).any(|w| w == b"select 1;"));
+        let mut decrypted = vec![];
+        config
+            .decrypt(Box::pin(std::io::Cursor::new(encrypted)))
+            .await
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .await
+            .unwrap();
+        decrypted
+    }
+
+    #[tokio::test]
+    async fn test_identity_env() {
+        let identity = x25519::Identity::generate();
+        let name = format!("BACKUP_TEST_IDENTITY_{}", std::process::id());
+        std::env::set_var(&name, identity.to_string().expose_secret());
+        let config = EncryptionConfig {
+            recipients: vec![],
+            identity_file: None,
+            identity_env: Some(name.clone()),
+            passphrase_file: None,
+            passphrase_env: None,
+        };
+        assert_eq!(roundtrip(&config).await, b"select 1;");
+
+        // only the recipient can encrypt, but not decrypt
+        let config = EncryptionConfig {
+            recipients: vec![identity.to_public().to_string()],
+            identity_env: None,
+            ..config
+        };
+        let body: ByteReader = Box::pin(std::io::Cursor::new(b"select 1;".to_vec()));
+        let mut encrypted = vec![];
+        let mut reader = config.encrypt(body).await.unwrap();
+        reader.read_to_end(&mut encrypted).await.unwrap();
+        assert!(config
+            .decrypt(Box::pin(std::io::Cursor::new(encrypted)))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_passphrase_file() {
+        let path = std::env::temp_dir().join(format!("backup-server-pass-{}", std::process::id()));
+        std::fs::write(&path, "correct horse battery staple\n").unwrap();
+        let config = EncryptionConfig {
+            recipients: vec![],
+            identity_file: None,
+            identity_env: None,
+            passphrase_file: Some(path.to_str().unwrap().to_string()),
+            passphrase_env: None,
+        };
+        assert_eq!(roundtrip(&config).await, b"select 1;");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
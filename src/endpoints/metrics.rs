@@ -2,8 +2,8 @@ use super::prelude::*;
 use super::AppState;
 use crate::realms::RealmsConfig;
 use lazy_static::lazy_static;
-use prometheus::{opts, register_int_gauge, register_int_gauge_vec};
-use prometheus::{Encoder, IntGauge, IntGaugeVec, Registry, TextEncoder};
+use prometheus::{opts, register_int_counter_vec, register_int_gauge, register_int_gauge_vec};
+use prometheus::{Encoder, IntCounterVec, IntGauge, IntGaugeVec, Registry, TextEncoder};
 
 lazy_static! {
     pub static ref UP: IntGauge =
@@ -23,10 +23,20 @@ lazy_static! {
     .expect("Can't create a REALM_SIZE_TOTAL");
     // date of the latest file
     pub static ref REALM_LATEST: IntGaugeVec = register_int_gauge_vec!(
-        opts!("backup_realm_timestamp", "Timestamp after the last update"),
+        opts!("backup_realm_timestamp", "Timestamp of the newest file in the realm"),
         &["realm"]
     )
     .expect("Can't create a REALM_LATEST");
+    // retried S3 requests of the server process, e.g. listing for the metrics.
+    // Pushes and pulls run by CLI commands are other processes, they only log the retries
+    pub static ref S3_RETRIES: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "backup_s3_retries_total",
+            "Number of S3 requests retried by the server process"
+        ),
+        &["operation"]
+    )
+    .expect("Can't create a S3_RETRIES");
 }
 
 #[instrument]
@@ -39,19 +49,23 @@ pub async fn to_string(config: &str) -> String {
     sr.register(Box::new(REALM_NUM_FILES.clone())).unwrap();
     sr.register(Box::new(REALM_SIZE_TOTAL.clone())).unwrap();
     sr.register(Box::new(REALM_LATEST.clone())).unwrap();
+    sr.register(Box::new(S3_RETRIES.clone())).unwrap();
 
     match RealmsConfig::from_toml(config) {
         Ok(cfg) => {
             for (key, realm) in cfg.realms {
                 match realm.stat().await {
                     Ok(stat) => {
-                        REALM_SIZE_TOTAL.with_label_values(&[&key]).set(stat.0);
+                        REALM_SIZE_TOTAL
+                            .with_label_values(&[&key])
+                            .set(stat.total_size);
                         REALM_NUM_FILES
                             .with_label_values(&[&key])
-                            .set(stat.1 as i64);
+                            .set(stat.count as i64);
+                        // empty realm reports zero, so staleness alerts fire for it too
                         REALM_LATEST
                             .with_label_values(&[&key])
-                            .set(stat.2.timestamp());
+                            .set(stat.newest.map_or(0, |t| t.timestamp()));
                     }
                     Err(err) => {
                         tracing::warn!("realm {} stat error: {}", key, err.to_string())
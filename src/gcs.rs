@@ -0,0 +1,554 @@
+use crate::s3::S3Object;
+use crate::storage::{read_part, ByteReader, StorageBackend, SyncReader};
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::TryStreamExt;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, LOCATION, RANGE};
+use reqwest::{Method, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::*;
+
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+/// chunk of the resumable upload, GCS requires a multiple of 256 KiB.
+/// Bodies of known smaller size are uploaded with a single request
+const CHUNK_SIZE: u64 = 32 * 256 * 1024;
+/// attempts to upload each chunk, the session is asked for the stored offset before a retry
+const CHUNK_ATTEMPTS: u32 = 3;
+
+/// service account key file, as downloaded from Google Cloud console
+#[derive(Deserialize)]
+struct ServiceAccount {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+/// claims of the JWT exchanged for the access token
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// object resource of JSON API
+#[derive(Deserialize)]
+struct GcsObject {
+    name: String,
+    /// size is serialized as string
+    #[serde(default)]
+    size: String,
+    updated: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl GcsObject {
+    fn into_s3_object(self) -> S3Object {
+        S3Object {
+            key: self.name,
+            last_modified: self.updated.unwrap_or_else(chrono::Utc::now),
+            size: self.size.parse().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectList {
+    #[serde(default)]
+    items: Vec<GcsObject>,
+    next_page_token: Option<String>,
+}
+
+/// state of the resumable upload session
+enum UploadStatus {
+    /// number of the bytes stored so far
+    Incomplete(u64),
+    Done(GcsObject),
+}
+
+/// Content-Range of the chunk at `offset`, `total` is only known with the last one
+fn content_range(offset: u64, len: u64, total: Option<u64>) -> String {
+    let total = total.map_or("*".to_string(), |total| total.to_string());
+    match len {
+        0 => format!("bytes */{}", total),
+        _ => format!("bytes {}-{}/{}", offset, offset + len - 1, total),
+    }
+}
+
+/// number of the stored bytes by the Range header of the incomplete upload, e.g. "bytes=0-42"
+fn stored_bytes(range: Option<&str>) -> anyhow::Result<u64> {
+    let range = match range {
+        Some(range) => range,
+        None => return Ok(0),
+    };
+    let last = range
+        .strip_prefix("bytes=0-")
+        .and_then(|last| last.parse::<u64>().ok())
+        .with_context(|| format!("invalid range of the upload session: {}", range))?;
+    Ok(last + 1)
+}
+
+/// GcsBucket keeps realm files in Google Cloud Storage bucket
+pub struct GcsBucket {
+    client: reqwest::Client,
+    /// service account, requests are anonymous without it (e.g. for fake-gcs-server)
+    account: Option<ServiceAccount>,
+    /// cached access token and the time it expires
+    token: Mutex<Option<(String, Instant)>>,
+    pub endpoint: Url,
+    pub bucket: String,
+}
+
+impl std::fmt::Debug for GcsBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GcsBucket")
+            .field("bucket", &self.bucket)
+            .finish()
+    }
+}
+
+impl GcsBucket {
+    /// creates new GCS bucket object
+    pub fn new(
+        credentials_file: Option<&str>,
+        bucket: &str,
+        endpoint: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        tracing::debug!("accessing gcs bucket {}", bucket);
+        let account = match credentials_file {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read credentials file {}", path))?;
+                let account = serde_json::from_str(&contents)
+                    .with_context(|| format!("invalid service account file {}", path))?;
+                Some(account)
+            }
+            None => None,
+        };
+        let endpoint =
+            Url::parse(endpoint.unwrap_or(DEFAULT_ENDPOINT)).context("invalid gcs endpoint")?;
+        let client = reqwest::Client::builder()
+            .build()
+            .context("Failed to create HTTP client")?;
+        Ok(Self {
+            client,
+            account,
+            token: Mutex::new(None),
+            endpoint,
+            bucket: bucket.to_string(),
+        })
+    }
+
+    /// URL of the JSON API resource, `segments` are appended to the path
+    fn url_of(&self, segments: &[&str]) -> Url {
+        let mut url = self.endpoint.clone();
+        url.path_segments_mut()
+            .expect("gcs url is a base")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    /// URL of the object metadata
+    fn object_url(&self, name: &str) -> Url {
+        self.url_of(&["storage", "v1", "b", &self.bucket, "o", name])
+    }
+
+    /// access token of the service account, refreshed when expired
+    async fn access_token(&self) -> anyhow::Result<Option<String>> {
+        let account = match &self.account {
+            Some(account) => account,
+            None => return Ok(None),
+        };
+        let mut token = self.token.lock().await;
+        if let Some((value, expires)) = token.as_ref() {
+            if *expires > Instant::now() {
+                return Ok(Some(value.clone()));
+            }
+        }
+        let now = chrono::Utc::now().timestamp();
+        let claims = Claims {
+            iss: &account.client_email,
+            scope: SCOPE,
+            aud: &account.token_uri,
+            iat: now,
+            exp: now + 3600,
+        };
+        let key = jsonwebtoken::EncodingKey::from_rsa_pem(account.private_key.as_bytes())
+            .context("invalid service account private key")?;
+        let assertion = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256),
+            &claims,
+            &key,
+        )?;
+        let res: TokenResponse = self
+            .client
+            .post(&account.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &assertion),
+            ])
+            .send()
+            .await
+            .context("failed to request access token")?
+            .error_for_status()
+            .context("failed to get access token")?
+            .json()
+            .await?;
+        // refresh a minute before the token actually expires
+        let expires = Instant::now() + Duration::from_secs(res.expires_in.saturating_sub(60));
+        *token = Some((res.access_token.clone(), expires));
+        Ok(Some(res.access_token))
+    }
+
+    async fn request(&self, method: Method, url: Url) -> anyhow::Result<reqwest::RequestBuilder> {
+        let req = self.client.request(method, url);
+        Ok(match self.access_token().await? {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        })
+    }
+
+    /// uploads the body with a single request
+    async fn put_media(
+        &self,
+        filename: &str,
+        body: ByteReader,
+        size: Option<u64>,
+    ) -> anyhow::Result<u64> {
+        let mut url = self.url_of(&["upload", "storage", "v1", "b", &self.bucket, "o"]);
+        url.query_pairs_mut()
+            .append_pair("uploadType", "media")
+            .append_pair("name", filename);
+        let stream = ReaderStream::new(SyncReader::new(body));
+        let mut req = self
+            .request(Method::POST, url)
+            .await?
+            .body(reqwest::Body::wrap_stream(stream));
+        if let Some(size) = size {
+            req = req.header(CONTENT_LENGTH, size);
+        }
+        let object: GcsObject = req
+            .send()
+            .await
+            .context("failed to upload object")?
+            .error_for_status()
+            .context("failed to upload object")?
+            .json()
+            .await?;
+        Ok(object.size.parse().unwrap_or_default())
+    }
+
+    /// uploads the body in chunks with the resumable upload session,
+    /// so a failed request only repeats its chunk
+    async fn put_resumable(
+        &self,
+        filename: &str,
+        mut body: ByteReader,
+        size: Option<u64>,
+    ) -> anyhow::Result<u64> {
+        let mut url = self.url_of(&["upload", "storage", "v1", "b", &self.bucket, "o"]);
+        url.query_pairs_mut()
+            .append_pair("uploadType", "resumable")
+            .append_pair("name", filename);
+        let mut req = self
+            .request(Method::POST, url)
+            .await?
+            .header(CONTENT_LENGTH, 0);
+        if let Some(size) = size {
+            req = req.header("X-Upload-Content-Length", size);
+        }
+        let res = req
+            .send()
+            .await
+            .context("failed to start upload session")?
+            .error_for_status()
+            .context("failed to start upload session")?;
+        let session = res
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .context("missing location of the upload session")?;
+        let session = Url::parse(session).context("invalid location of the upload session")?;
+        let mut offset = 0;
+        loop {
+            let chunk = read_part(&mut body, CHUNK_SIZE)
+                .await
+                .context("failed to read upload stream")?;
+            let len = chunk.len() as u64;
+            // the stream ends with a short chunk, which is possibly empty
+            let total = (len < CHUNK_SIZE).then_some(offset + len);
+            if let Some(object) = self.put_chunk(&session, offset, chunk, total).await? {
+                return Ok(object.size.parse().unwrap_or_default());
+            }
+            if total.is_some() {
+                anyhow::bail!("upload session of {} is not finished", filename);
+            }
+            offset += len;
+        }
+    }
+
+    /// uploads the chunk at `offset`, returns the object once the last one is stored
+    async fn put_chunk(
+        &self,
+        session: &Url,
+        offset: u64,
+        chunk: Bytes,
+        total: Option<u64>,
+    ) -> anyhow::Result<Option<GcsObject>> {
+        let end = offset + chunk.len() as u64;
+        let mut stored = offset;
+        let mut attempt = 1;
+        loop {
+            let rest = chunk.slice((stored - offset) as usize..);
+            let res = self.put_range(session, stored, rest, total).await;
+            match res {
+                Ok(UploadStatus::Done(object)) => return Ok(Some(object)),
+                Ok(UploadStatus::Incomplete(bytes)) if bytes >= end => return Ok(None),
+                // the server stored only a part of the chunk, the rest is sent again
+                Ok(UploadStatus::Incomplete(bytes)) if bytes > stored => stored = bytes,
+                Ok(UploadStatus::Incomplete(bytes)) => {
+                    anyhow::bail!("upload session stored {} bytes of {}", bytes, end)
+                }
+                Err(e) if attempt < CHUNK_ATTEMPTS => {
+                    warn!(
+                        "chunk at {} failed (attempt {} of {}), resuming: {:#}",
+                        stored, attempt, CHUNK_ATTEMPTS, e
+                    );
+                    tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                    attempt += 1;
+                    // the chunk may be stored partially, or even completely
+                    match self.put_range(session, 0, Bytes::new(), None).await {
+                        Ok(UploadStatus::Done(object)) => return Ok(Some(object)),
+                        Ok(UploadStatus::Incomplete(bytes)) if bytes >= end => return Ok(None),
+                        Ok(UploadStatus::Incomplete(bytes)) => stored = bytes.max(offset),
+                        Err(e) => warn!("failed to query upload session: {:#}", e),
+                    }
+                }
+                Err(e) => return Err(e.context("failed to upload object")),
+            }
+        }
+    }
+
+    /// sends the bytes at `offset` to the upload session, empty ones query its status
+    async fn put_range(
+        &self,
+        session: &Url,
+        offset: u64,
+        bytes: Bytes,
+        total: Option<u64>,
+    ) -> anyhow::Result<UploadStatus> {
+        let range = content_range(offset, bytes.len() as u64, total);
+        // the session URL authorizes the upload by itself
+        let res = self
+            .client
+            .put(session.clone())
+            .header(CONTENT_LENGTH, bytes.len())
+            .header(CONTENT_RANGE, range)
+            .body(bytes)
+            .send()
+            .await?;
+        if res.status() == StatusCode::PERMANENT_REDIRECT {
+            let range = res.headers().get(RANGE).and_then(|r| r.to_str().ok());
+            return Ok(UploadStatus::Incomplete(stored_bytes(range)?));
+        }
+        Ok(UploadStatus::Done(res.error_for_status()?.json().await?))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for GcsBucket {
+    #[instrument(ret, level = "info")]
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<S3Object>> {
+        let mut out = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut url = self.url_of(&["storage", "v1", "b", &self.bucket, "o"]);
+            url.query_pairs_mut().append_pair("prefix", prefix);
+            if let Some(token) = &page_token {
+                url.query_pairs_mut().append_pair("pageToken", token);
+            }
+            let list: ObjectList = self
+                .request(Method::GET, url)
+                .await?
+                .send()
+                .await
+                .context("failed to list objects")?
+                .error_for_status()
+                .context("failed to list objects")?
+                .json()
+                .await?;
+            out.extend(list.items.into_iter().map(GcsObject::into_s3_object));
+            page_token = list.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        // sort out by last modified
+        out.sort_by_key(|o| std::cmp::Reverse(o.last_modified));
+        Ok(out)
+    }
+
+    #[instrument(ret, level = "info", skip(body))]
+    async fn put(
+        &self,
+        filename: &str,
+        body: ByteReader,
+        size: Option<u64>,
+    ) -> anyhow::Result<u64> {
+        match size {
+            Some(size) if size <= CHUNK_SIZE => self.put_media(filename, body, Some(size)).await,
+            _ => self.put_resumable(filename, body, size).await,
+        }
+    }
+
+    #[instrument(level = "info")]
+    async fn get(&self, filename: &str) -> anyhow::Result<ByteReader> {
+        let mut url = self.object_url(filename);
+        url.query_pairs_mut().append_pair("alt", "media");
+        let res = self
+            .request(Method::GET, url)
+            .await?
+            .send()
+            .await
+            .context("failed to get object")?
+            .error_for_status()
+            .context("failed to get object")?;
+        let stream = res.bytes_stream().map_err(std::io::Error::other);
+        Ok(Box::pin(StreamReader::new(stream)))
+    }
+
+    #[instrument(ret, level = "warn")]
+    async fn delete(&self, filename: &str) -> anyhow::Result<()> {
+        self.request(Method::DELETE, self.object_url(filename))
+            .await?
+            .send()
+            .await
+            .context("failed to delete object")?
+            .error_for_status()
+            .context("failed to delete object")?;
+        Ok(())
+    }
+
+    #[instrument(ret, level = "info")]
+    async fn head(&self, filename: &str) -> anyhow::Result<Option<S3Object>> {
+        let res = self
+            .request(Method::GET, self.object_url(filename))
+            .await?
+            .send()
+            .await
+            .context("failed to get object metadata")?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let object: GcsObject = res
+            .error_for_status()
+            .context("failed to get object metadata")?
+            .json()
+            .await?;
+        Ok(Some(object.into_s3_object()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_urls_and_listing() {
+        let bucket = GcsBucket::new(None, "backups", Some("http://localhost:4443")).unwrap();
+        assert_eq!(
+            bucket.object_url("db/dump 1.sql").as_str(),
+            "http://localhost:4443/storage/v1/b/backups/o/db%2Fdump%201.sql"
+        );
+
+        let list: ObjectList = serde_json::from_str(
+            r#"{
+  "kind": "storage#objects",
+  "nextPageToken": "CgtkYi9kdW1wLTIuc3Fs",
+  "items": [
+    {"kind": "storage#object", "name": "db/dump-1.sql", "bucket": "backups",
+     "size": "1024", "updated": "2024-04-03T11:30:00.123Z"}
+  ]
+}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            list.next_page_token.as_deref(),
+            Some("CgtkYi9kdW1wLTIuc3Fs")
+        );
+        let objects: Vec<S3Object> = list
+            .items
+            .into_iter()
+            .map(GcsObject::into_s3_object)
+            .collect();
+        assert_eq!(objects[0].key, "db/dump-1.sql");
+        assert_eq!(objects[0].size, 1024);
+        assert_eq!(
+            objects[0].last_modified.to_rfc3339(),
+            "2024-04-03T11:30:00.123+00:00"
+        );
+
+        let empty: ObjectList = serde_json::from_str(r#"{"kind": "storage#objects"}"#).unwrap();
+        assert!(empty.items.is_empty());
+        assert!(empty.next_page_token.is_none());
+    }
+
+    #[test]
+    fn test_upload_ranges() {
+        assert_eq!(content_range(0, CHUNK_SIZE, None), "bytes 0-8388607/*");
+        assert_eq!(
+            content_range(CHUNK_SIZE, 10, Some(CHUNK_SIZE + 10)),
+            "bytes 8388608-8388617/8388618"
+        );
+        // the status query, and the end of the stream at the chunk boundary
+        assert_eq!(content_range(0, 0, None), "bytes */*");
+        assert_eq!(
+            content_range(CHUNK_SIZE, 0, Some(CHUNK_SIZE)),
+            "bytes */8388608"
+        );
+
+        assert_eq!(stored_bytes(None).unwrap(), 0);
+        assert_eq!(stored_bytes(Some("bytes=0-8388607")).unwrap(), CHUNK_SIZE);
+        assert!(stored_bytes(Some("bytes=10-20")).is_err());
+    }
+
+    /// runs against fake-gcs-server, e.g.
+    /// `docker run -p 4443:4443 fsouza/fake-gcs-server -scheme http -public-host localhost:4443`,
+    /// with the bucket "backups" created, and `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_fake_gcs_server() {
+        let bucket = GcsBucket::new(None, "backups", Some("http://localhost:4443")).unwrap();
+
+        let body: ByteReader = Box::pin(std::io::Cursor::new(b"select 1;".to_vec()));
+        assert_eq!(bucket.put("test/db-1.sql", body, Some(9)).await.unwrap(), 9);
+        // unknown size is uploaded in chunks
+        let contents = vec![7u8; (2 * CHUNK_SIZE + 1) as usize];
+        let body: ByteReader = Box::pin(std::io::Cursor::new(contents.clone()));
+        let size = bucket.put("test/db-2.sql", body, None).await.unwrap();
+        assert_eq!(size, contents.len() as u64);
+        bucket.delete("test/db-2.sql").await.unwrap();
+
+        let list = bucket.list("test/").await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].size, 9);
+        assert!(bucket.head("test/db-1.sql").await.unwrap().is_some());
+        assert!(bucket.head("test/db-0.sql").await.unwrap().is_none());
+        bucket.delete("test/db-1.sql").await.unwrap();
+        assert!(bucket.list("test/").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+use crate::s3::S3Object;
+use crate::storage::{is_partial, partial_name, ByteReader, StorageBackend};
+use anyhow::Context;
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+/// LocalDir stores realm files under a directory of the local filesystem
+/// (which can also be a mounted network share, like NFS)
+#[derive(Debug, Clone)]
+pub struct LocalDir {
+    pub root: PathBuf,
+}
+
+impl LocalDir {
+    /// creates new local directory storage object
+    pub fn new(root: &str) -> anyhow::Result<Self> {
+        tracing::debug!("accessing local directory {}", root);
+        let root = PathBuf::from(root);
+        if !root.is_dir() {
+            anyhow::bail!("local directory {} does not exist", root.display());
+        }
+        Ok(Self { root })
+    }
+
+    /// full path of the file with the given key
+    fn path_of(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalDir {
+    /// lists files which keys (paths relative to the root) start with the prefix,
+    /// sorted by last modified, newest first
+    #[instrument(ret, level = "info")]
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<S3Object>> {
+        let mut out = vec![];
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("failed to read directory {}", dir.display()))?;
+            while let Some(entry) = entries.next_entry().await? {
+                let meta = entry.metadata().await?;
+                if meta.is_dir() {
+                    dirs.push(entry.path());
+                    continue;
+                }
+                let key = key_of(&self.root, &entry.path());
+                if !key.starts_with(prefix) || is_partial(&key) {
+                    continue;
+                }
+                out.push(S3Object {
+                    key,
+                    last_modified: meta.modified()?.into(),
+                    size: meta.len() as i64,
+                });
+            }
+        }
+        // sort out by last modified
+        out.sort_by_key(|o| std::cmp::Reverse(o.last_modified));
+        Ok(out)
+    }
+
+    #[instrument(ret, level = "info", skip(body))]
+    async fn put(
+        &self,
+        filename: &str,
+        mut body: ByteReader,
+        _size: Option<u64>,
+    ) -> anyhow::Result<u64> {
+        let target = self.path_of(filename);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .context("failed to create target directory")?;
+        }
+        let tmp = target.with_file_name(partial_name(
+            &target.file_name().unwrap_or_default().to_string_lossy(),
+        ));
+        let mut file = tokio::fs::File::create(&tmp)
+            .await
+            .context("failed to create file")?;
+        let size = match tokio::io::copy(&mut body, &mut file).await {
+            Ok(size) => size,
+            Err(e) => {
+                // incomplete file is not kept
+                let _ = tokio::fs::remove_file(&tmp).await;
+                return Err(anyhow::Error::new(e).context("failed to write file"));
+            }
+        };
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, &target)
+            .await
+            .context("failed to rename file")?;
+        Ok(size)
+    }
+
+    #[instrument(level = "info")]
+    async fn get(&self, filename: &str) -> anyhow::Result<ByteReader> {
+        let file = tokio::fs::File::open(self.path_of(filename))
+            .await
+            .context("failed to open file")?;
+        Ok(Box::pin(file))
+    }
+
+    #[instrument(ret, level = "warn")]
+    async fn delete(&self, filename: &str) -> anyhow::Result<()> {
+        tokio::fs::remove_file(self.path_of(filename))
+            .await
+            .context("failed to delete file")?;
+        Ok(())
+    }
+
+    #[instrument(ret, level = "info")]
+    async fn head(&self, filename: &str) -> anyhow::Result<Option<S3Object>> {
+        let meta = match tokio::fs::metadata(self.path_of(filename)).await {
+            Ok(meta) if meta.is_file() => meta,
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(anyhow::Error::new(e).context("failed to read metadata")),
+        };
+        Ok(Some(S3Object {
+            key: filename.to_string(),
+            last_modified: meta.modified()?.into(),
+            size: meta.len() as i64,
+        }))
+    }
+}
+
+/// key of the file is its path relative to the root, with "/" as separator
+fn key_of(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_put_list_get_delete() {
+        let root = std::env::temp_dir().join(format!("backup-server-local-{}", std::process::id()));
+        let storage = root.join("storage");
+        std::fs::create_dir_all(&storage).unwrap();
+
+        let dir = LocalDir::new(storage.to_str().unwrap()).unwrap();
+        for key in ["project/db-1.sql", "other/db-2.sql"] {
+            let body: ByteReader = Box::pin(std::io::Cursor::new(b"select 1;".to_vec()));
+            assert_eq!(dir.put(key, body, None).await.unwrap(), 9);
+        }
+
+        let list = dir.list("project/").await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].key, "project/db-1.sql");
+        assert_eq!(list[0].size, 9);
+
+        let mut contents = String::new();
+        let mut body = dir.get("project/db-1.sql").await.unwrap();
+        body.read_to_string(&mut contents).await.unwrap();
+        assert_eq!(contents, "select 1;");
+
+        let head = dir.head("project/db-1.sql").await.unwrap().unwrap();
+        assert_eq!(head.size, 9);
+        assert!(dir.head("project/db-0.sql").await.unwrap().is_none());
+
+        dir.delete("project/db-1.sql").await.unwrap();
+        assert!(dir.list("project/").await.unwrap().is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
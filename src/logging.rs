@@ -4,9 +4,11 @@ use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 pub fn start(defaults: &str) {
     let env_filter = EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new(defaults));
-    let is_terminal = atty::is(Stream::Stdout);
+    // logs go to stderr, so stdout is left for the backup streams
+    let is_terminal = atty::is(Stream::Stderr);
     let subscriber = tracing_subscriber::fmt::fmt()
         .with_env_filter(env_filter)
+        .with_writer(std::io::stderr)
         .with_ansi(is_terminal)
         .with_span_events(fmt::format::FmtSpan::CLOSE) // enable durations
         .finish();
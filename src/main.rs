@@ -1,11 +1,26 @@
 mod args;
+mod azure;
+mod check;
+mod compression;
+mod crypto;
 mod endpoints;
+mod gcs;
+mod local;
 mod logging;
+mod output;
+mod process;
 mod realms;
+mod retention;
 mod s3;
+mod secrets;
+mod select;
+mod sftp;
+mod storage;
+mod webdav;
 
-use args::Command;
+use args::{Command, ConfigCommand};
 use realms::RealmsConfig;
+use select::PullTarget;
 use std::path::Path;
 
 #[tokio::main]
@@ -21,6 +36,28 @@ async fn main() -> anyhow::Result<()> {
     };
 
     match opt.cmd {
+        Command::Config {
+            cmd:
+                ConfigCommand::Check {
+                    connect,
+                    write,
+                    config,
+                },
+        } => {
+            let report = check::check(&config, connect, write).await?;
+            for problem in &report.problems {
+                println!("{}", problem);
+            }
+            if !report.problems.is_empty() {
+                anyhow::bail!(
+                    "{} problems found in {} realms of {}",
+                    report.problems.len(),
+                    report.realms,
+                    config
+                );
+            }
+            println!("{} realms are valid", report.realms);
+        }
         Command::OpenApi => {
             use endpoints::openapi::*;
             println!("{}", serde_json::to_string(&openapi()).unwrap());
@@ -37,25 +74,66 @@ async fn main() -> anyhow::Result<()> {
             let cfg = RealmsConfig::from_toml(&config).expect("realms config");
             if name.is_empty() {
                 for (name, realm) in cfg.realms {
-                    let (size, total, last_update) = realm.stat().await?;
-                    println!(
-                        "[{}] {:?}, {} bytes,{} files",
-                        name, last_update, size, total
-                    )
+                    let stat = realm.stat().await?;
+                    println!("[{}] {}", name, stat)
                 }
             } else {
                 let errmsg = format!("unknown realm {}, found {:?}", name, cfg.realms.keys());
                 let realm = cfg.realms.get(&name).expect(&errmsg);
-                let (size, total, last_update) = realm.stat().await?;
-                println!(
-                    "[{}] {:?}, {} bytes, {} files",
-                    name, last_update, size, total,
-                );
+                let stat = realm.stat().await?;
+                println!("[{}] {}", name, stat);
+            }
+        }
+
+        Command::List {
+            name,
+            format,
+            config,
+        } => {
+            let cfg = RealmsConfig::from_toml(&config).expect("realms config");
+            let errmsg = format!("unknown realm {}, found {:?}", name, cfg.realms.keys());
+            let realm = cfg.realms.get(&name).expect(&errmsg);
+            let list = realm.list().await?;
+            let now = chrono::Utc::now();
+            print!("{}", output::objects_to_string(&list, format, now)?);
+        }
+
+        Command::Prune {
+            name,
+            dry_run,
+            config,
+        } => {
+            let cfg = RealmsConfig::from_toml(&config).expect("realms config");
+            if !name.is_empty() && !cfg.realms.contains_key(&name) {
+                panic!("unknown realm {}, found {:?}", name, cfg.realms.keys());
+            }
+            // failure of one realm does not stop pruning of the others
+            let mut failed = vec![];
+            for (realm_name, realm) in &cfg.realms {
+                if !name.is_empty() && *realm_name != name {
+                    continue;
+                }
+                match realm.prune(dry_run).await {
+                    Ok(Some(plan)) => {
+                        print!("{}", output::plan_to_string(realm_name, &plan, dry_run))
+                    }
+                    Ok(None) => println!("[{}] no lifetime configured", realm_name),
+                    Err(e) => {
+                        eprintln!("[{}] prune failed: {:#}", realm_name, e);
+                        failed.push(realm_name.as_str());
+                    }
+                }
+            }
+            if !failed.is_empty() {
+                anyhow::bail!("failed to prune realms {}", failed.join(", "));
             }
         }
 
         Command::Push {
             file,
+            stdin,
+            exec,
+            key_name,
             name,
             clean,
             exchange_dir,
@@ -65,25 +143,67 @@ async fn main() -> anyhow::Result<()> {
             let cfg = RealmsConfig::from_toml(&config).expect("realms config");
             let errmsg = format!("unknown realm {}, found {:?}", name, cfg.realms.keys());
             let realm = cfg.realms.get(&name).expect(&errmsg);
-            let path = std::path::Path::new(&exchange_dir).join(&file);
-            let size = realm.push(&path).await?;
-            println!("Uploaded {} bytes", size);
-            if clean {
-                if let Err(err) = std::fs::remove_file(&path) {
-                    println!("Failed to remove {}: {}", path.display(), err);
+            let key_name = key_name.unwrap_or_default();
+            if let Some(file) = file {
+                let path = Path::new(&exchange_dir.unwrap_or_default()).join(&file);
+                let pushed = realm.push(&path).await?;
+                println!("Uploaded {}", pushed);
+                if clean {
+                    if let Err(err) = std::fs::remove_file(&path) {
+                        println!("Failed to remove {}: {}", path.display(), err);
+                    }
                 }
+            } else if let Some(command) = exec {
+                let body = process::spawn_output(&command)?;
+                let pushed = realm.push_stream(&key_name, body).await?;
+                println!("Uploaded {}", pushed);
+            } else if stdin {
+                let pushed = realm
+                    .push_stream(&key_name, Box::pin(tokio::io::stdin()))
+                    .await?;
+                println!("Uploaded {}", pushed);
             }
         }
         Command::Pull {
             name,
+            key,
+            at,
+            nth,
+            stdout,
+            exec,
             exchange_dir,
             config,
         } => {
             let cfg = RealmsConfig::from_toml(&config).expect("realms config");
             let errmsg = format!("unknown realm {}, found {:?}", name, cfg.realms.keys());
             let realm = cfg.realms.get(&name).expect(&errmsg);
-            let output = realm.pull(Path::new(&exchange_dir)).await?;
-            println!("Saved as {}", output.display());
+            let target = match (key, at, nth) {
+                (Some(key), _, _) => PullTarget::Key(key),
+                (_, Some(at), _) => PullTarget::At(at),
+                (_, _, Some(nth)) => PullTarget::Nth(nth as usize),
+                _ => PullTarget::Latest,
+            };
+            if stdout {
+                let (key, size) = realm.pull_stream(&target, &mut tokio::io::stdout()).await?;
+                eprintln!("Streamed {} ({} bytes)", key, size);
+            } else if let Some(command) = exec {
+                let mut input = process::spawn_input(&command)?;
+                match realm.pull_stream(&target, &mut input.stdin).await {
+                    Ok((key, size)) => {
+                        input.finish().await?;
+                        println!("Restored {} ({} bytes)", key, size);
+                    }
+                    Err(e) => {
+                        // failure of the command explains the broken stream better
+                        input.abort().await?;
+                        return Err(e);
+                    }
+                }
+            } else {
+                let exchange_dir = exchange_dir.unwrap_or_default();
+                let output = realm.pull(Path::new(&exchange_dir), &target).await?;
+                println!("Saved as {}", output.display());
+            }
         }
     }
     Ok(())
@@ -0,0 +1,190 @@
+use crate::retention::RetentionPlan;
+use crate::s3::S3Object;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// how the command results are printed
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputFormat {
+    /// aligned columns
+    #[default]
+    Table,
+    /// JSON array, for scripts
+    Json,
+}
+
+/// realm file, as printed by the list command
+#[derive(Serialize)]
+struct ListEntry<'a> {
+    key: &'a str,
+    /// size in bytes
+    size: i64,
+    last_modified: DateTime<Utc>,
+    /// age in seconds
+    age: i64,
+}
+
+/// formats the realm files for printing
+pub fn objects_to_string(
+    objects: &[S3Object],
+    format: OutputFormat,
+    now: DateTime<Utc>,
+) -> anyhow::Result<String> {
+    match format {
+        OutputFormat::Json => {
+            let entries: Vec<ListEntry> = objects
+                .iter()
+                .map(|o| ListEntry {
+                    key: &o.key,
+                    size: o.size,
+                    last_modified: o.last_modified,
+                    age: (now - o.last_modified).num_seconds(),
+                })
+                .collect();
+            Ok(serde_json::to_string_pretty(&entries)? + "\n")
+        }
+        OutputFormat::Table => {
+            let width = objects
+                .iter()
+                .map(|o| o.key.len())
+                .max()
+                .unwrap_or(0)
+                .max(3);
+            let mut out = format!(
+                "{:<width$}  {:>10}  {:<19}  {:>7}\n",
+                "KEY", "SIZE", "LAST MODIFIED", "AGE"
+            );
+            for o in objects {
+                out += &format!(
+                    "{:<width$}  {:>10}  {:<19}  {:>7}\n",
+                    o.key,
+                    format_size(o.size),
+                    o.last_modified.format("%Y-%m-%d %H:%M:%S"),
+                    format_age((now - o.last_modified).num_seconds()),
+                );
+            }
+            Ok(out)
+        }
+    }
+}
+
+/// formats the retention plan of the realm, files are listed newest first
+pub fn plan_to_string(realm: &str, plan: &RetentionPlan, dry_run: bool) -> String {
+    let mut out = String::new();
+    for (action, list) in [("keep", &plan.keep), ("delete", &plan.delete)] {
+        for o in list {
+            out += &format!(
+                "[{}] {} {} ({}, {})\n",
+                realm,
+                action,
+                o.key,
+                format_size(o.size),
+                o.last_modified.format("%Y-%m-%d %H:%M:%S"),
+            );
+        }
+    }
+    let (deleted, freed) = match dry_run {
+        true => ("to delete", "would be freed"),
+        false => ("deleted", "freed"),
+    };
+    out += &format!(
+        "[{}] {} files kept, {} files {}, {} {}\n",
+        realm,
+        plan.keep.len(),
+        plan.delete.len(),
+        deleted,
+        format_size(plan.bytes_freed()),
+        freed,
+    );
+    out
+}
+
+/// size in bytes, in binary units
+pub fn format_size(size: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", size),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+/// age in seconds, in two largest units
+pub fn format_age(seconds: i64) -> String {
+    match seconds {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 86400 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s => format!("{}d {}h", s / 86400, s % 86400 / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_objects_to_string() {
+        let now: DateTime<Utc> = "2024-04-03T12:00:00Z".parse().unwrap();
+        let objects = vec![
+            S3Object {
+                key: "db/dump-2.sql".to_string(),
+                last_modified: "2024-04-03T11:30:00Z".parse().unwrap(),
+                size: 3 * 1024 * 1024 / 2,
+            },
+            S3Object {
+                key: "db/dump-1.sql".to_string(),
+                last_modified: "2024-04-01T09:00:00Z".parse().unwrap(),
+                size: 9,
+            },
+        ];
+        assert_eq!(
+            objects_to_string(&objects, OutputFormat::Table, now).unwrap(),
+            "KEY                  SIZE  LAST MODIFIED            AGE
+db/dump-2.sql     1.5 MiB  2024-04-03 11:30:00      30m
+db/dump-1.sql         9 B  2024-04-01 09:00:00    2d 3h
+"
+        );
+
+        let json = objects_to_string(&objects, OutputFormat::Json, now).unwrap();
+        let entries: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(entries[1]["key"], "db/dump-1.sql");
+        assert_eq!(entries[1]["size"], 9);
+        assert_eq!(entries[1]["last_modified"], "2024-04-01T09:00:00Z");
+        assert_eq!(entries[1]["age"], 183600);
+    }
+
+    #[test]
+    fn test_plan_to_string() {
+        let object = |key: &str, size| S3Object {
+            key: key.to_string(),
+            last_modified: "2024-04-01T09:00:00Z".parse().unwrap(),
+            size,
+        };
+        let plan = RetentionPlan {
+            keep: vec![object("db/dump-2.sql", 9)],
+            delete: vec![object("db/dump-1.sql", 2048)],
+        };
+        assert_eq!(
+            plan_to_string("db", &plan, true),
+            "[db] keep db/dump-2.sql (9 B, 2024-04-01 09:00:00)
+[db] delete db/dump-1.sql (2.0 KiB, 2024-04-01 09:00:00)
+[db] 1 files kept, 1 files to delete, 2.0 KiB would be freed
+"
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+        assert_eq!(format_age(59), "59s");
+        assert_eq!(format_age(3 * 3600 + 120), "3h 2m");
+    }
+}
@@ -0,0 +1,185 @@
+use crate::storage::{checked_reader, ByteReader};
+use anyhow::Context;
+use std::process::Stdio;
+use tokio::process::{Child, ChildStdin, Command};
+
+/// shell command in its own process group, so it is killed with all its processes
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .process_group(0)
+        .kill_on_drop(true);
+    cmd
+}
+
+/// ShellChild kills the process group of the shell once dropped, as killing the shell
+/// alone leaves e.g. the commands of its pipeline running
+struct ShellChild(Child);
+
+impl ShellChild {
+    /// kills the shell with all its processes, unless it is already waited for
+    fn kill(&self) {
+        if let Some(pid) = self.0.id() {
+            // SAFETY: the process group of the child is not reused until it is waited for
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+impl Drop for ShellChild {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// runs the shell command, returning its standard output as the stream
+pub fn spawn_output(command: &str) -> anyhow::Result<ByteReader> {
+    let mut child = shell(command)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map(ShellChild)
+        .with_context(|| format!("failed to run `{}`", command))?;
+    let stdout = child.0.stdout.take().context("missing command output")?;
+    let command = command.to_string();
+    // the output is incomplete if the command exits with error
+    let exit = async move {
+        let status = child.0.wait().await?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "command `{}` failed: {}",
+                command, status
+            )));
+        }
+        Ok(())
+    };
+    Ok(checked_reader(Box::pin(stdout), exit))
+}
+
+/// standard input of the child process
+pub struct CommandInput {
+    command: String,
+    child: ShellChild,
+    pub stdin: ChildStdin,
+}
+
+impl CommandInput {
+    /// closes the input and waits for the command, failing if it exits with error
+    pub async fn finish(self) -> anyhow::Result<()> {
+        let Self {
+            command,
+            mut child,
+            stdin,
+        } = self;
+        drop(stdin);
+        let status = child.0.wait().await?;
+        if !status.success() {
+            anyhow::bail!("command `{}` failed: {}", command, status);
+        }
+        Ok(())
+    }
+
+    /// kills the command before its input is closed, so it never takes the incomplete
+    /// input for the whole one. Fails if the command has already exited with error,
+    /// which explains the broken input
+    pub async fn abort(self) -> anyhow::Result<()> {
+        let Self {
+            command,
+            mut child,
+            stdin,
+        } = self;
+        if let Some(status) = child.0.try_wait()? {
+            if !status.success() {
+                anyhow::bail!("command `{}` failed: {}", command, status);
+            }
+            return Ok(());
+        }
+        child.kill();
+        child.0.wait().await?;
+        drop(stdin);
+        Ok(())
+    }
+}
+
+/// runs the shell command, which reads from the returned input
+pub fn spawn_input(command: &str) -> anyhow::Result<CommandInput> {
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map(ShellChild)
+        .with_context(|| format!("failed to run `{}`", command))?;
+    let stdin = child.0.stdin.take().context("missing command input")?;
+    Ok(CommandInput {
+        command: command.to_string(),
+        child,
+        stdin,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_spawn_output() {
+        let mut out = String::new();
+        spawn_output("printf 'select 1;'")
+            .unwrap()
+            .read_to_string(&mut out)
+            .await
+            .unwrap();
+        assert_eq!(out, "select 1;");
+
+        let mut out = String::new();
+        let err = spawn_output("printf 'select'; exit 3")
+            .unwrap()
+            .read_to_string(&mut out)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("exit status: 3"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_spawn_input() {
+        let mut input = spawn_input("grep -q 'select 1;'").unwrap();
+        input.stdin.write_all(b"select 1;\n").await.unwrap();
+        input.finish().await.unwrap();
+
+        let mut input = spawn_input("grep -q 'select 2;'").unwrap();
+        input.stdin.write_all(b"select 1;\n").await.unwrap();
+        let err = input.finish().await.unwrap_err();
+        assert!(err.to_string().contains("exit status: 1"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_abort() {
+        // the command is killed before it reads the end of the input
+        let path = std::env::temp_dir().join(format!("backup-server-abort-{}", std::process::id()));
+        let mut input =
+            spawn_input(&format!("cat > /dev/null && touch {}", path.display())).unwrap();
+        input.stdin.write_all(b"select").await.unwrap();
+        input.abort().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!path.exists());
+
+        // the commands of the pipeline are killed along with the shell
+        let mut input = spawn_input(&format!(
+            "cat | (cat > /dev/null && touch {})",
+            path.display()
+        ))
+        .unwrap();
+        input.stdin.write_all(b"select").await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        input.abort().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!path.exists());
+
+        let input = spawn_input("exit 3").unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let err = input.abort().await.unwrap_err();
+        assert!(err.to_string().contains("exit status: 3"), "{}", err);
+    }
+}
@@ -1,42 +1,317 @@
+use crate::azure::AzureContainer;
+use crate::compression::Compression;
+use crate::crypto::{self, EncryptionConfig};
+use crate::gcs::GcsBucket;
+use crate::local::LocalDir;
+use crate::retention::RetentionPlan;
 use crate::s3::*;
+use crate::secrets;
+use crate::select::{default_timestamp_format, select, LatestStrategy, PullTarget};
+use crate::sftp::{SftpDir, SshAuth};
+use crate::storage::{
+    checked_reader, counting_reader, drained_reader, partial_name, ByteReader, StorageBackend,
+};
+use crate::webdav::WebDavDir;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use std::collections::BTreeMap as Map;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "transport")]
 pub enum RealmLocation {
     S3 {
-        /// S3 access key
-        access_key: String,
-        /// S3 secret key
-        secret_access_key: String,
+        /// S3 credentials, the keys or the source to take them from
+        #[serde(flatten)]
+        credentials: S3Credentials,
         /// S3 bucket name
         bucket: String,
         /// S3 region name
         #[serde(flatten)]
         region: S3Region,
+        /// multipart upload settings
+        #[serde(flatten)]
+        multipart: MultipartConfig,
+        /// retry policy of the requests
+        #[serde(default)]
+        retry: RetryConfig,
+    },
+    Local {
+        /// root directory of the realm files, can be a mounted network share
+        path: String,
+    },
+    #[serde(rename = "SFTP")]
+    Sftp {
+        /// SSH server host name
+        host: String,
+        /// SSH server port
+        #[serde(default = "default_ssh_port")]
+        port: u16,
+        /// SSH user name
+        user: String,
+        /// private key file path, password authentication is used if missing
+        #[serde(default)]
+        key_file: Option<String>,
+        /// passphrase of the key file, or user password if there is no key file
+        #[serde(default)]
+        password: Option<String>,
+        /// known_hosts file to verify server key, `~/.ssh/known_hosts` if missing
+        #[serde(default)]
+        known_hosts: Option<String>,
+        /// accepts any server key without verification, unsafe outside of tests
+        #[serde(default)]
+        insecure_accept_any_host_key: bool,
+        /// remote directory of the realm files
+        path: String,
+    },
+    #[serde(rename = "WebDAV")]
+    WebDav {
+        /// URL of the WebDAV collection of the realm files
+        url: String,
+        /// user name for basic authentication
+        #[serde(default)]
+        user: Option<String>,
+        /// password for basic authentication
+        #[serde(default)]
+        password: Option<String>,
+    },
+    Azure {
+        /// storage account name
+        account: String,
+        /// storage account access key
+        #[serde(default)]
+        access_key: Option<String>,
+        /// shared access signature, used when there is no access key
+        #[serde(default)]
+        sas_token: Option<String>,
+        /// blob container name
+        container: String,
+        /// blob service URL, e.g. "http://127.0.0.1:10000/devstoreaccount1" for Azurite
+        #[serde(default)]
+        endpoint: Option<String>,
     },
+    #[serde(rename = "GCS")]
+    Gcs {
+        /// service account JSON key file path, requests are anonymous if missing
+        #[serde(default)]
+        credentials_file: Option<String>,
+        /// GCS bucket name
+        bucket: String,
+        /// JSON API URL, e.g. "http://localhost:4443" for fake-gcs-server
+        #[serde(default)]
+        endpoint: Option<String>,
+    },
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+/// field and message of the config problem
+pub type Problem = (&'static str, String);
+
+fn check_empty(field: &'static str, value: &str) -> Option<Problem> {
+    value
+        .trim()
+        .is_empty()
+        .then(|| (field, "is empty".to_string()))
+}
+
+fn check_url(field: &'static str, value: Option<&str>) -> Option<Problem> {
+    let e = reqwest::Url::parse(value?).err()?;
+    Some((field, format!("invalid URL: {}", e)))
 }
 
 impl RealmLocation {
-    fn is_s3(&self) -> bool {
-        matches!(self, Self::S3 { .. })
+    /// config keys of the transport, None if it is unknown
+    pub fn fields(transport: &str) -> Option<Vec<&'static str>> {
+        let fields = match transport {
+            "S3" => [
+                S3Credentials::FIELDS,
+                MultipartConfig::FIELDS,
+                &["bucket", "region", "endpoint", "retry"],
+            ]
+            .concat(),
+            "Local" => vec!["path"],
+            "SFTP" => vec![
+                "host",
+                "port",
+                "user",
+                "key_file",
+                "password",
+                "known_hosts",
+                "insecure_accept_any_host_key",
+                "path",
+            ],
+            "WebDAV" => vec!["url", "user", "password"],
+            "Azure" => vec![
+                "account",
+                "access_key",
+                "sas_token",
+                "container",
+                "endpoint",
+            ],
+            "GCS" => vec!["credentials_file", "bucket", "endpoint"],
+            _ => return None,
+        };
+        Some(fields)
     }
 
-    fn get_bucket(&self) -> anyhow::Result<Bucket> {
+    /// problems of the transport settings, which are found without connecting
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut out = vec![];
         match self {
             Self::S3 {
+                credentials,
+                bucket,
+                region,
+                multipart,
+                retry,
+            } => {
+                if let Err(e) = credentials.check() {
+                    out.push(("credentials", format!("{:#}", e)));
+                }
+                out.extend(check_empty("bucket", bucket));
+                if let Err(e) = region.to_region() {
+                    let field = match region {
+                        S3Region::Region(_) => "region",
+                        S3Region::Endpoint(_) => "endpoint",
+                    };
+                    out.push((field, format!("{:#}", e)));
+                }
+                out.extend(multipart.problems());
+                out.extend(retry.problems());
+            }
+            Self::Local { path } => out.extend(check_empty("path", path)),
+            Self::Sftp {
+                host,
+                user,
+                key_file,
+                password,
+                ..
+            } => {
+                out.extend(check_empty("host", host));
+                out.extend(check_empty("user", user));
+                if key_file.is_none() && password.is_none() {
+                    let message = "either key_file or password is required".to_string();
+                    out.push(("key_file", message));
+                }
+            }
+            Self::WebDav { url, .. } => out.extend(check_url("url", Some(url))),
+            Self::Azure {
+                account,
                 access_key,
-                secret_access_key,
+                sas_token,
+                container,
+                endpoint,
+            } => {
+                out.extend(check_empty("account", account));
+                out.extend(check_empty("container", container));
+                if access_key.is_none() && sas_token.is_none() {
+                    let message = "either access_key or sas_token is required".to_string();
+                    out.push(("access_key", message));
+                }
+                out.extend(check_url("endpoint", endpoint.as_deref()));
+            }
+            Self::Gcs {
+                bucket, endpoint, ..
+            } => {
+                out.extend(check_empty("bucket", bucket));
+                out.extend(check_url("endpoint", endpoint.as_deref()));
+            }
+        }
+        out
+    }
+
+    /// storage backend of the transport
+    pub async fn backend(&self) -> anyhow::Result<Box<dyn StorageBackend>> {
+        match self {
+            Self::S3 {
+                credentials,
+                bucket,
+                region,
+                multipart,
+                retry,
+            } => Ok(Box::new(Bucket::new(
+                credentials,
                 bucket,
                 region,
-            } => return Bucket::new(access_key, secret_access_key, bucket, region),
+                multipart,
+                retry,
+            )?)),
+            Self::Local { path } => Ok(Box::new(LocalDir::new(path)?)),
+            Self::Sftp {
+                host,
+                port,
+                user,
+                key_file,
+                password,
+                known_hosts,
+                insecure_accept_any_host_key,
+                path,
+            } => {
+                let auth = match (key_file, password) {
+                    (Some(file), passphrase) => SshAuth::Key {
+                        file,
+                        passphrase: passphrase.as_deref(),
+                    },
+                    (None, Some(password)) => SshAuth::Password(password),
+                    (None, None) => anyhow::bail!("either key_file or password is required"),
+                };
+                let dir = SftpDir::connect(
+                    host,
+                    *port,
+                    user,
+                    auth,
+                    known_hosts.as_deref(),
+                    *insecure_accept_any_host_key,
+                    path,
+                )
+                .await?;
+                Ok(Box::new(dir))
+            }
+            Self::WebDav {
+                url,
+                user,
+                password,
+            } => Ok(Box::new(WebDavDir::new(
+                url,
+                user.as_deref(),
+                password.as_deref(),
+            )?)),
+            Self::Azure {
+                account,
+                access_key,
+                sas_token,
+                container,
+                endpoint,
+            } => Ok(Box::new(AzureContainer::new(
+                account,
+                access_key.as_deref(),
+                sas_token.as_deref(),
+                container,
+                endpoint.as_deref(),
+            )?)),
+            Self::Gcs {
+                credentials_file,
+                bucket,
+                endpoint,
+            } => Ok(Box::new(GcsBucket::new(
+                credentials_file.as_deref(),
+                bucket,
+                endpoint.as_deref(),
+            )?)),
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// files over `max_files` or older than `max_age` are deleted, unless kept by `min_files`
+/// or grandfather-father-son rules (`keep_daily` etc). When only GFS rules are set,
+/// every file they don't keep is deleted
+#[derive(Debug, Default, Deserialize)]
 pub struct RealmLifetime {
     /// max age of the files in days
     #[serde(default)]
@@ -44,6 +319,21 @@ pub struct RealmLifetime {
     /// max number of files to keep
     #[serde(default)]
     pub max_files: u64,
+    /// number of the newest files which are never deleted, the newest file is always kept
+    #[serde(default)]
+    pub min_files: u64,
+    /// keep the newest file of each of the last N days
+    #[serde(default)]
+    pub keep_daily: u64,
+    /// keep the newest file of each of the last N ISO weeks
+    #[serde(default)]
+    pub keep_weekly: u64,
+    /// keep the newest file of each of the last N months
+    #[serde(default)]
+    pub keep_monthly: u64,
+    /// keep the newest file of each of the last N years
+    #[serde(default)]
+    pub keep_yearly: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -54,105 +344,560 @@ pub struct Realm {
     /// what name should the uploaded file contain to be identified as a part of the realm
     #[serde(default)]
     pub contains: String,
+    /// how the latest backup is chosen on pull
+    #[serde(default)]
+    pub latest: LatestStrategy,
+    /// format of the timestamp in the file names, when the latest backup is chosen by it
+    #[serde(default = "default_timestamp_format")]
+    pub timestamp_format: String,
+    /// compression of the files, "zstd" or "gzip"
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// compression level, the default level of the algorithm if missing
+    #[serde(default)]
+    pub compression_level: Option<i32>,
+    /// client-side encryption of the files
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
     #[serde(flatten)]
     pub location: RealmLocation,
     #[serde(flatten)]
-    pub lifetime: Option<RealmLifetime>,
+    pub lifetime: RealmLifetime,
+}
+
+impl RealmLifetime {
+    /// config keys of the rules
+    pub const FIELDS: &'static [&'static str] = &[
+        "max_age",
+        "max_files",
+        "min_files",
+        "keep_daily",
+        "keep_weekly",
+        "keep_monthly",
+        "keep_yearly",
+    ];
+
+    /// whether no rule deletes files, as when none is configured
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age == 0
+            && self.max_files == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+            && self.keep_yearly == 0
+    }
+
+    /// contradictions between the rules
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut out = vec![];
+        if self.max_files > 0 && self.min_files > self.max_files {
+            out.push((
+                "min_files",
+                format!("is greater than max_files {}", self.max_files),
+            ));
+        }
+        out
+    }
+}
+
+/// summary of the files stored in the realm
+#[derive(Debug, Clone, Default)]
+pub struct RealmStat {
+    /// total size of the files in bytes
+    pub total_size: i64,
+    /// number of the files
+    pub count: u32,
+    /// last modified time of the newest file, None when the realm is empty
+    pub newest: Option<DateTime<Utc>>,
+    /// last modified time of the oldest file, None when the realm is empty
+    pub oldest: Option<DateTime<Utc>>,
+    /// key of the newest file
+    pub newest_key: Option<String>,
+}
+
+impl std::fmt::Display for RealmStat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes, {} files", self.total_size, self.count)?;
+        if let (Some(newest), Some(oldest), Some(key)) =
+            (self.newest, self.oldest, &self.newest_key)
+        {
+            write!(f, ", newest {} at {}, oldest at {}", key, newest, oldest)?;
+        }
+        Ok(())
+    }
+}
+
+/// result of the upload
+#[derive(Debug, Clone)]
+pub struct Pushed {
+    /// key of the uploaded file
+    pub key: String,
+    /// size of the original stream
+    pub size: u64,
+    /// number of bytes uploaded, after compression and encryption
+    pub uploaded: u64,
+}
+
+impl std::fmt::Display for Pushed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes as {}", self.uploaded, self.key)?;
+        if self.uploaded != self.size && self.uploaded > 0 {
+            write!(
+                f,
+                ", {} bytes originally, ratio {:.2}",
+                self.size,
+                self.size as f64 / self.uploaded as f64
+            )?;
+        }
+        Ok(())
+    }
 }
 
 impl Realm {
-    pub async fn push(&self, file_path: &PathBuf) -> anyhow::Result<u64> {
-        if self.location.is_s3() {
-            let bucket = self.location.get_bucket()?;
-            if !format!("{}", file_path.display()).contains(&self.contains) {
-                anyhow::bail!(
-                    "file {} is expected to contain {} to fit the realm",
-                    file_path.display(),
-                    self.contains
-                );
-            }
-
-            // remote path is prefix + file name
-            let remote_path = format!(
-                "{}{}",
-                self.prefix,
-                file_path.file_name().unwrap().to_str().unwrap()
+    /// config keys of the realm, besides the transport and lifetime ones
+    pub const FIELDS: &'static [&'static str] = &[
+        "transport",
+        "prefix",
+        "contains",
+        "latest",
+        "timestamp_format",
+        "compression",
+        "compression_level",
+        "encryption",
+    ];
+
+    /// problems of the realm settings, which are found without connecting
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut out = self.location.problems();
+        out.extend(self.lifetime.problems());
+        let items = chrono::format::StrftimeItems::new(&self.timestamp_format);
+        if items
+            .into_iter()
+            .any(|item| item == chrono::format::Item::Error)
+        {
+            out.push(("timestamp_format", "invalid format".to_string()));
+        }
+        match (self.compression, self.compression_level) {
+            (None, Some(_)) => out.push((
+                "compression_level",
+                "is set without compression".to_string(),
+            )),
+            (Some(compression), Some(level)) if !compression.levels().contains(&level) => {
+                let levels = compression.levels();
+                out.push((
+                    "compression_level",
+                    format!("is out of {}..={}", levels.start(), levels.end()),
+                ))
+            }
+            _ => {}
+        }
+        if let Some(encryption) = &self.encryption {
+            if let Err(e) = encryption.check() {
+                out.push(("encryption", format!("{:#}", e)));
+            }
+        }
+        out
+    }
+
+    pub async fn push(&self, file_path: &PathBuf) -> anyhow::Result<Pushed> {
+        self.push_into(self.location.backend().await?.as_ref(), file_path)
+            .await
+    }
+
+    /// uploads the stream as the file with the given name
+    pub async fn push_stream(&self, name: &str, body: ByteReader) -> anyhow::Result<Pushed> {
+        self.push_stream_into(self.location.backend().await?.as_ref(), name, body)
+            .await
+    }
+
+    pub async fn pull(&self, exchange_dir: &Path, target: &PullTarget) -> anyhow::Result<PathBuf> {
+        self.pull_from(
+            self.location.backend().await?.as_ref(),
+            exchange_dir,
+            target,
+        )
+        .await
+    }
+
+    /// deletes files outside of the realm lifetime, or only plans it on dry run
+    pub async fn prune(&self, dry_run: bool) -> anyhow::Result<Option<RetentionPlan>> {
+        self.prune_from(self.location.backend().await?.as_ref(), dry_run)
+            .await
+    }
+
+    /// files of the realm, newest first
+    pub async fn list(&self) -> anyhow::Result<Vec<S3Object>> {
+        self.list_from(self.location.backend().await?.as_ref())
+            .await
+    }
+
+    /// streams the backup into the writer, returns its key and size
+    pub async fn pull_stream<W: AsyncWrite + Unpin + Send>(
+        &self,
+        target: &PullTarget,
+        writer: &mut W,
+    ) -> anyhow::Result<(String, u64)> {
+        self.pull_stream_from(self.location.backend().await?.as_ref(), target, writer)
+            .await
+    }
+
+    // return stat of the realm
+    pub async fn stat(&self) -> anyhow::Result<RealmStat> {
+        self.stat_from(self.location.backend().await?.as_ref())
+            .await
+    }
+
+    async fn push_into(
+        &self,
+        storage: &dyn StorageBackend,
+        file_path: &PathBuf,
+    ) -> anyhow::Result<Pushed> {
+        if !format!("{}", file_path.display()).contains(&self.contains) {
+            anyhow::bail!(
+                "file {} is expected to contain {} to fit the realm",
+                file_path.display(),
+                self.contains
             );
-            if let Some(lifetime) = &self.lifetime {
-                let mut list = bucket.list(&self.prefix).await?;
-                let mut needs_reload = false;
-                if lifetime.max_age > 0 {
-                    let now = chrono::Utc::now();
-                    let cutoff =
-                        now - std::time::Duration::from_secs(lifetime.max_age * 24 * 60 * 60);
-                    for obj in &list {
-                        if obj.last_modified < cutoff {
-                            let _ = bucket.delete_file(&obj.key).await;
-                            needs_reload = true;
-                        }
-                    }
-                    if needs_reload {
-                        list = bucket.list(&self.prefix).await?;
-                    }
-                }
-                if lifetime.max_files > 0 {
-                    if list.len() as u64 >= lifetime.max_files {
-                        let num_to_delete = list.len() - lifetime.max_files as usize;
-                        // delete first files in the list
-                        for obj in list.into_iter().take(num_to_delete) {
-                            let _ = bucket.delete_file(&obj.key).await;
-                        }
-                    }
-                }
+        }
+
+        let name = file_path.file_name().unwrap().to_str().unwrap();
+        let file = tokio::fs::File::open(file_path)
+            .await
+            .context("failed to open local file")?;
+        let size = file.metadata().await?.len();
+        self.upload(storage, name, Box::pin(file), Some(size)).await
+    }
+
+    async fn push_stream_into(
+        &self,
+        storage: &dyn StorageBackend,
+        name: &str,
+        body: ByteReader,
+    ) -> anyhow::Result<Pushed> {
+        if !name.contains(&self.contains) {
+            anyhow::bail!(
+                "name {} is expected to contain {} to fit the realm",
+                name,
+                self.contains
+            );
+        }
+        self.upload(storage, name, body, None).await
+    }
+
+    /// uploads the stream as the file with the given name, encoded as configured for the realm
+    async fn upload(
+        &self,
+        storage: &dyn StorageBackend,
+        name: &str,
+        body: ByteReader,
+        size: Option<u64>,
+    ) -> anyhow::Result<Pushed> {
+        let (body, read) = counting_reader(body);
+        let (name, body, size) = self.encode(name, body, size).await?;
+        let key = self.remote_path(storage, &name).await;
+        let uploaded = storage.put(&key, body, size).await?;
+        self.apply_lifetime(storage).await;
+        Ok(Pushed {
+            key,
+            size: read.load(Ordering::Relaxed),
+            uploaded,
+        })
+    }
+
+    /// encodes the upload as configured for the realm: compressed, then encrypted.
+    /// The file name gets the extension of each encoding, so it is reversed on pull.
+    /// The size is unknown after encoding
+    async fn encode(
+        &self,
+        name: &str,
+        mut body: ByteReader,
+        mut size: Option<u64>,
+    ) -> anyhow::Result<(String, ByteReader, Option<u64>)> {
+        let mut name = name.to_string();
+        if let Some(compression) = self.compression {
+            body = compression.compress(body, self.compression_level);
+            name += compression.extension();
+            size = None;
+        }
+        if let Some(encryption) = &self.encryption {
+            body = encryption.encrypt(body).await?;
+            name += crypto::EXTENSION;
+            size = None;
+        }
+        Ok((name, body, size))
+    }
+
+    /// encodings of the downloaded file by its key extensions, with the decoded key.
+    /// Only the encodings configured for the realm are reversed, so the files compressed
+    /// by the backup command itself, e.g. `dump.sql.gz`, are pulled as they are
+    fn decodings<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> (&'a str, Option<&'a EncryptionConfig>, Option<Compression>) {
+        let mut key = key;
+        let mut encryption = None;
+        if let Some(config) = &self.encryption {
+            if let Some(decrypted) = key.strip_suffix(crypto::EXTENSION) {
+                encryption = Some(config);
+                key = decrypted;
+            }
+        }
+        let mut compression = None;
+        if self.compression.is_some() {
+            if let Some((algorithm, decompressed)) = Compression::of_key(key) {
+                compression = Some(algorithm);
+                key = decompressed;
             }
-            return bucket.put_file(&remote_path, file_path).await;
         }
+        (key, encryption, compression)
+    }
 
-        anyhow::bail!("transport not supported yet")
+    /// decodes the downloaded file, returns the decoded key
+    async fn decode(
+        &self,
+        key: &str,
+        mut body: ByteReader,
+    ) -> anyhow::Result<(String, ByteReader)> {
+        let (key, encryption, compression) = self.decodings(key);
+        if encryption.is_none() && compression.is_none() {
+            return Ok((key.to_string(), body));
+        }
+        // the downloaded stream is verified at its end, which the decoders do not read
+        let (raw, drain) = drained_reader(body);
+        body = raw;
+        if let Some(encryption) = encryption {
+            body = encryption.decrypt(body).await?;
+        }
+        if let Some(compression) = compression {
+            body = compression.decompress(body);
+        }
+        Ok((key.to_string(), checked_reader(body, drain)))
     }
 
-    pub async fn pull(&self, exchange_dir: &Path) -> anyhow::Result<PathBuf> {
-        if self.location.is_s3() {
-            let bucket = self.location.get_bucket()?;
-            let list = bucket.list(&self.prefix).await?;
-            let mut latest = "".to_string();
-            for obj in list {
-                if !obj.key.contains(&self.contains) {
-                    continue;
+    /// remote path is prefix + file name, warns if it is going to be overwritten.
+    /// The check is best-effort, as write-only credentials are not allowed to read metadata
+    async fn remote_path(&self, storage: &dyn StorageBackend, name: &str) -> String {
+        let remote_path = format!("{}{}", self.prefix, name);
+        match storage.head(&remote_path).await {
+            Ok(Some(existing)) => tracing::warn!(
+                "overwriting {} ({} bytes, {})",
+                existing.key,
+                existing.size,
+                existing.last_modified
+            ),
+            Ok(None) => {}
+            Err(e) => tracing::debug!("failed to check {}: {:#}", remote_path, e),
+        }
+        remote_path
+    }
+
+    /// retention is applied after the upload, so the new file is counted and never lost
+    async fn apply_lifetime(&self, storage: &dyn StorageBackend) {
+        if let Err(e) = self.prune_from(storage, false).await {
+            tracing::warn!("retention failed: {:#}", e);
+        }
+    }
+
+    /// applies the lifetime of the realm, files are not deleted on dry run.
+    /// returns None if the lifetime of the realm is unlimited
+    async fn prune_from(
+        &self,
+        storage: &dyn StorageBackend,
+        dry_run: bool,
+    ) -> anyhow::Result<Option<RetentionPlan>> {
+        let lifetime = &self.lifetime;
+        if lifetime.is_unlimited() {
+            return Ok(None);
+        }
+        let list = self.list_from(storage).await?;
+        let plan = RetentionPlan::new(lifetime, list, Utc::now());
+        if dry_run {
+            return Ok(Some(plan));
+        }
+        let mut failed = 0;
+        for obj in &plan.delete {
+            if let Err(e) = storage.delete(&obj.key).await {
+                tracing::warn!("failed to delete expired {}: {:#}", obj.key, e);
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            anyhow::bail!("failed to delete {} of {} files", failed, plan.delete.len());
+        }
+        Ok(Some(plan))
+    }
+
+    /// files of the realm: under its prefix and containing the realm marker
+    async fn list_from(&self, storage: &dyn StorageBackend) -> anyhow::Result<Vec<S3Object>> {
+        let mut list = storage.list(&self.prefix).await?;
+        list.retain(|obj| obj.key.contains(&self.contains));
+        Ok(list)
+    }
+
+    /// downloads the file into the partial file first, which is renamed once complete.
+    /// Downloads of files that are stored as is are resumed, while the file is not overwritten
+    async fn pull_from(
+        &self,
+        storage: &dyn StorageBackend,
+        exchange_dir: &Path,
+        target: &PullTarget,
+    ) -> anyhow::Result<PathBuf> {
+        let obj = self.select_from(storage, target).await?;
+        let (key, encryption, compression) = self.decodings(&obj.key);
+        let local_file_path: PathBuf = Path::new(exchange_dir).join(key);
+        let partial = local_file_path.with_file_name(partial_name(
+            &local_file_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy(),
+        ));
+        // version of the file the partial one is downloaded from
+        let etag_path = partial.with_extension("etag");
+        let etag = match encryption.is_none() && compression.is_none() {
+            true => storage.etag(&obj.key).await?,
+            false => None,
+        };
+        let resumed = match &etag {
+            Some(etag) => {
+                self.resume_from(storage, &obj.key, etag, &partial, &etag_path)
+                    .await?
+            }
+            None => None,
+        };
+        let (mut file, mut body) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                let file = tokio::fs::File::create(&partial)
+                    .await
+                    .with_context(|| format!("failed to create {}", partial.display()))?;
+                match &etag {
+                    Some(etag) => tokio::fs::write(&etag_path, etag).await?,
+                    None => remove_if_exists(&etag_path).await?,
                 }
-                latest = obj.key.clone();
+                let (_, body) = self.decode(&obj.key, storage.get(&obj.key).await?).await?;
+                (file, body)
             }
-            if !latest.is_empty() {
-                let local_file_path: PathBuf = Path::new(exchange_dir).join(latest.clone());
-                let _ = bucket.get_file(&latest, &local_file_path).await?;
-                return Ok(local_file_path);
+        };
+        let res = async {
+            tokio::io::copy(&mut body, &mut file)
+                .await
+                .context("failed to download backup")?;
+            file.sync_all().await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(e) = res {
+            if etag.is_some() && !is_corrupted(&e) {
+                tracing::warn!("partial download is kept in {}", partial.display());
+            } else {
+                for path in [&partial, &etag_path] {
+                    if let Err(e) = remove_if_exists(path).await {
+                        tracing::warn!("failed to remove {}: {}", path.display(), e);
+                    }
+                }
             }
-            anyhow::bail!("no backups")
+            return Err(e);
         }
+        tokio::fs::rename(&partial, &local_file_path)
+            .await
+            .with_context(|| format!("failed to rename to {}", local_file_path.display()))?;
+        remove_if_exists(&etag_path).await?;
+        Ok(local_file_path)
+    }
 
-        anyhow::bail!("transport not supported yet")
+    /// opens the partial file to append the rest of the download to,
+    /// if it was downloaded from the same version of the file
+    async fn resume_from(
+        &self,
+        storage: &dyn StorageBackend,
+        key: &str,
+        etag: &str,
+        partial: &Path,
+        etag_path: &Path,
+    ) -> anyhow::Result<Option<(tokio::fs::File, ByteReader)>> {
+        let offset = match tokio::fs::metadata(partial).await {
+            Ok(metadata) if metadata.len() > 0 => metadata.len(),
+            _ => return Ok(None),
+        };
+        if tokio::fs::read_to_string(etag_path).await.ok().as_deref() != Some(etag) {
+            return Ok(None);
+        }
+        let prefix = tokio::fs::File::open(partial).await?;
+        let rest = match storage
+            .get_range(key, etag, offset, Box::pin(prefix))
+            .await?
+        {
+            Some(rest) => rest,
+            None => return Ok(None),
+        };
+        tracing::info!("resuming download of {} from {} bytes", key, offset);
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(partial)
+            .await
+            .with_context(|| format!("failed to open {}", partial.display()))?;
+        Ok(Some((file, rest)))
     }
 
-    // return stat of the trealm
-    pub async fn stat(&self) -> anyhow::Result<(i64, u32, chrono::DateTime<chrono::Utc>)> {
-        if self.location.is_s3() {
-            let bucket: Bucket = self.location.get_bucket()?;
-            let list = bucket.list(&self.prefix).await?;
-            let mut total_size = 0;
-            let mut total_count = 0u32;
-            let mut last_modified = chrono::Utc::now();
-            for obj in list {
-                if !obj.key.contains(&self.contains) {
-                    continue;
-                }
-                total_size += obj.size;
-                total_count += 1;
-                last_modified = std::cmp::max(last_modified, obj.last_modified);
+    async fn pull_stream_from<W: AsyncWrite + Unpin + Send>(
+        &self,
+        storage: &dyn StorageBackend,
+        target: &PullTarget,
+        writer: &mut W,
+    ) -> anyhow::Result<(String, u64)> {
+        let obj = self.select_from(storage, target).await?;
+        let (key, mut body) = self.decode(&obj.key, storage.get(&obj.key).await?).await?;
+        let size = tokio::io::copy(&mut body, writer)
+            .await
+            .context("failed to stream backup")?;
+        writer.flush().await?;
+        Ok((key, size))
+    }
+
+    /// the file of the realm to pull
+    async fn select_from(
+        &self,
+        storage: &dyn StorageBackend,
+        target: &PullTarget,
+    ) -> anyhow::Result<S3Object> {
+        let list = self.list_from(storage).await?;
+        match select(target, self.latest, &self.timestamp_format, &list) {
+            Some(obj) => Ok(obj.clone()),
+            None => anyhow::bail!("no backups matching {} in {} files", target, list.len()),
+        }
+    }
+
+    async fn stat_from(&self, storage: &dyn StorageBackend) -> anyhow::Result<RealmStat> {
+        let list = self.list_from(storage).await?;
+        let mut stat = RealmStat::default();
+        for obj in list {
+            stat.total_size += obj.size;
+            stat.count += 1;
+            if stat.newest.is_none_or(|t| obj.last_modified > t) {
+                stat.newest = Some(obj.last_modified);
+                stat.newest_key = Some(obj.key.clone());
+            }
+            if stat.oldest.is_none_or(|t| obj.last_modified < t) {
+                stat.oldest = Some(obj.last_modified);
             }
-            return Ok((total_size, total_count, last_modified));
         }
+        Ok(stat)
+    }
+}
+
+/// whether the download failed because it does not match its checksum
+fn is_corrupted(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::InvalidData)
+    })
+}
 
-        anyhow::bail!("transport not supported yet")
+async fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
     }
 }
 
@@ -163,16 +908,42 @@ pub struct RealmsConfig {
 
 // constructor
 impl RealmsConfig {
+    /// reads the config file, resolving the secrets referenced in it
     pub fn from_toml(file_path: &str) -> anyhow::Result<Self> {
         tracing::info!("reading config {}", file_path);
-        let out = toml::from_str(&std::fs::read_to_string(&file_path)?)?;
-        Ok(out)
+        let mut value: toml::Value = toml::from_str(&std::fs::read_to_string(file_path)?)?;
+        secrets::resolve(&mut value)?;
+        Ok(value.try_into()?)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::MemoryStorage;
+
+    /// Local realm of the database dumps, with the settings appended to the common ones
+    fn local_realm(settings: &str) -> Realm {
+        let contents = format!(
+            r#"
+transport = "Local"
+path = "/nonexistent"
+prefix = "project-db/"
+contains = ".sql"
+{}"#,
+            settings
+        );
+        toml::from_str(&contents).unwrap()
+    }
+
+    /// empty temporary directory, removed by the test in the end
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("backup-server-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
     #[test]
     fn test_config() {
@@ -187,10 +958,276 @@ secret_access_key = ""
 bucket = ""
 endpoint = "https://eu2.contabostorage.com"
 max_files = 7
+keep_monthly = 6
+part_size_mb = 64
+
+[realms.media.retry]
+max_attempts = 6
+retry_statuses = [503]
+
+[realms.logs]
+transport = "S3"
+prefix = "project-logs"
+bucket = "logs"
+region = "eu-central-1"
+credentials = "profile"
+profile = "backup"
 
 "#;
 
-        let config: RealmsConfig = toml::from_str(&contents).unwrap();
+        let config: RealmsConfig = toml::from_str(contents).unwrap();
         println!("{:?}", config);
+        let lifetime = &config.realms["media"].lifetime;
+        assert_eq!((lifetime.max_files, lifetime.keep_monthly), (7, 6));
+        assert!(!lifetime.is_unlimited());
+        assert!(config.realms["logs"].lifetime.is_unlimited());
+        match &config.realms["media"].location {
+            RealmLocation::S3 {
+                credentials,
+                multipart,
+                retry,
+                ..
+            } => {
+                assert_eq!(credentials.source(), CredentialsSource::Static);
+                assert_eq!(multipart.part_size_mb, 64);
+                assert_eq!(multipart.upload_parallelism, 4);
+                assert_eq!((retry.max_attempts, retry.base_delay_ms), (6, 200));
+                assert_eq!(retry.retry_statuses, vec![503]);
+            }
+            _ => panic!("media realm is expected to use S3"),
+        }
+        match &config.realms["logs"].location {
+            RealmLocation::S3 { credentials, .. } => {
+                assert_eq!(credentials.source(), CredentialsSource::Profile);
+                assert_eq!(credentials.profile.as_deref(), Some("backup"));
+            }
+            _ => panic!("logs realm is expected to use S3"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_pull_stat() {
+        let realm = local_realm("");
+        let storage = MemoryStorage::default();
+        let dir = test_dir("realm");
+        let file_path = dir.join("dump-1.sql");
+        std::fs::write(&file_path, "select 1;").unwrap();
+
+        let pushed = realm.push_into(&storage, &file_path).await.unwrap();
+        assert_eq!((pushed.size, pushed.uploaded), (9, 9));
+        assert_eq!(storage.keys(), vec!["project-db/dump-1.sql"]);
+        assert!(realm
+            .push_into(&storage, &dir.join("dump-1.txt"))
+            .await
+            .is_err());
+        let body: ByteReader = Box::pin(std::io::Cursor::new(b"select 2;".to_vec()));
+        let pushed = realm
+            .push_stream_into(&storage, "dump-2.sql", body)
+            .await
+            .unwrap();
+        assert_eq!(pushed.key, "project-db/dump-2.sql");
+        storage.delete("project-db/dump-2.sql").await.unwrap();
+
+        let stat = realm.stat_from(&storage).await.unwrap();
+        assert_eq!((stat.total_size, stat.count), (9, 1));
+        assert_eq!(stat.newest_key.as_deref(), Some("project-db/dump-1.sql"));
+        assert!(stat.newest.unwrap() <= Utc::now());
+
+        std::fs::remove_file(&file_path).unwrap();
+        std::fs::create_dir_all(dir.join("project-db")).unwrap();
+        let pulled = realm
+            .pull_from(&storage, &dir, &PullTarget::Latest)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&pulled).unwrap(), "select 1;");
+        let mut out = vec![];
+        let (key, size) = realm
+            .pull_stream_from(&storage, &PullTarget::Latest, &mut out)
+            .await
+            .unwrap();
+        assert_eq!((key.as_str(), size), ("project-db/dump-1.sql", 9));
+        assert_eq!(out, b"select 1;");
+
+        // the corrupted download is not kept
+        storage.corrupt("project-db/dump-1.sql");
+        std::fs::remove_file(&pulled).unwrap();
+        let err = realm
+            .pull_from(&storage, &dir, &PullTarget::Latest)
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("checksum mismatch"),
+            "{:#}",
+            err
+        );
+        assert!(!pulled.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resumed_pull() {
+        let realm = local_realm("");
+        let storage = MemoryStorage::default();
+        storage.insert("project-db/dump-1.sql", b"select 1;", Utc::now());
+        let etag = storage
+            .etag("project-db/dump-1.sql")
+            .await
+            .unwrap()
+            .unwrap();
+        let dir = test_dir("resume");
+        std::fs::create_dir_all(dir.join("project-db")).unwrap();
+        let partial = dir.join("project-db/.dump-1.sql.part");
+        let etag_path = dir.join("project-db/.dump-1.sql.etag");
+        let pull = || realm.pull_from(&storage, &dir, &PullTarget::Latest);
+
+        // the rest of the file is appended to the partial one
+        std::fs::write(&partial, "sel").unwrap();
+        std::fs::write(&etag_path, &etag).unwrap();
+        let pulled = pull().await.unwrap();
+        assert_eq!(std::fs::read_to_string(&pulled).unwrap(), "select 1;");
+        assert!(!partial.exists() && !etag_path.exists());
+
+        // the partial file does not match the checksum of the whole one
+        std::fs::write(&partial, "XXX").unwrap();
+        std::fs::write(&etag_path, &etag).unwrap();
+        let err = pull().await.unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("checksum mismatch"),
+            "{:#}",
+            err
+        );
+        assert!(!partial.exists() && !etag_path.exists());
+
+        // the file was overwritten since the partial download
+        std::fs::write(&partial, "XXX").unwrap();
+        std::fs::write(&etag_path, "outdated").unwrap();
+        let pulled = pull().await.unwrap();
+        assert_eq!(std::fs::read_to_string(&pulled).unwrap(), "select 1;");
+        assert!(!partial.exists() && !etag_path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_push_retention() {
+        let realm = local_realm("max_files = 2");
+        let storage = MemoryStorage::default();
+        let now = chrono::Utc::now();
+        for (key, age) in [
+            ("project-db/dump-1.sql", 3),
+            ("project-db/dump-2.sql", 2),
+            ("project-db/notes.txt", 10),
+        ] {
+            storage.insert(key, b"select 1;", now - chrono::Duration::days(age));
+        }
+        let dir = test_dir("retention");
+        let file_path = dir.join("dump-3.sql");
+        std::fs::write(&file_path, "select 1;").unwrap();
+
+        let plan = realm.prune_from(&storage, true).await.unwrap().unwrap();
+        assert_eq!(plan.delete.len(), 0);
+        assert_eq!(storage.keys().len(), 3);
+
+        realm.push_into(&storage, &file_path).await.unwrap();
+        // the oldest backup is deleted, files outside of the realm are untouched
+        assert_eq!(
+            storage.keys(),
+            vec![
+                "project-db/dump-2.sql",
+                "project-db/dump-3.sql",
+                "project-db/notes.txt"
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_push_pull() {
+        let passphrase = format!("BACKUP_TEST_PASSPHRASE_{}", std::process::id());
+        std::env::set_var(&passphrase, "correct horse battery staple");
+        let realm = local_realm(&format!(
+            "[encryption]\npassphrase_env = \"{}\"",
+            passphrase
+        ));
+        let storage = MemoryStorage::default();
+        let body: ByteReader = Box::pin(std::io::Cursor::new(b"select 1;".to_vec()));
+        realm
+            .push_stream_into(&storage, "dump-1.sql", body)
+            .await
+            .unwrap();
+        assert_eq!(storage.keys(), vec!["project-db/dump-1.sql.age"]);
+
+        let mut out = vec![];
+        let (key, _) = realm
+            .pull_stream_from(&storage, &PullTarget::Latest, &mut out)
+            .await
+            .unwrap();
+        assert_eq!(
+            (key.as_str(), out.as_slice()),
+            ("project-db/dump-1.sql", &b"select 1;"[..])
+        );
+
+        // the decryption ends before the corrupted end of the file
+        storage.append("project-db/dump-1.sql.age", b"garbage");
+        let err = realm
+            .pull_stream_from(&storage, &PullTarget::Latest, &mut vec![])
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("checksum mismatch"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compressed_push_pull() {
+        let realm = local_realm("compression = \"zstd\"\ncompression_level = 19");
+        let storage = MemoryStorage::default();
+        let contents = "insert into t values (1);\n".repeat(1000);
+        let body: ByteReader = Box::pin(std::io::Cursor::new(contents.clone().into_bytes()));
+        let pushed = realm
+            .push_stream_into(&storage, "dump-1.sql", body)
+            .await
+            .unwrap();
+        assert_eq!(pushed.key, "project-db/dump-1.sql.zst");
+        assert_eq!(pushed.size, contents.len() as u64);
+        assert!(pushed.uploaded < pushed.size / 10);
+        assert!(pushed.to_string().contains("ratio"));
+
+        let mut out = vec![];
+        let (key, size) = realm
+            .pull_stream_from(&storage, &PullTarget::Latest, &mut out)
+            .await
+            .unwrap();
+        assert_eq!(key, "project-db/dump-1.sql");
+        assert_eq!(size, contents.len() as u64);
+        assert_eq!(out, contents.as_bytes());
+
+        // the decompression ends before the corrupted end of the file
+        storage.append("project-db/dump-1.sql.zst", b"garbage");
+        let err = realm
+            .pull_stream_from(&storage, &PullTarget::Latest, &mut vec![])
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("checksum mismatch"),
+            "{:#}",
+            err
+        );
+        let body: ByteReader = Box::pin(std::io::Cursor::new(contents.clone().into_bytes()));
+        realm
+            .push_stream_into(&storage, "dump-1.sql", body)
+            .await
+            .unwrap();
+
+        // without the compression, the file is pulled as it is stored
+        let realm = local_realm("");
+        let mut out = vec![];
+        let (key, _) = realm
+            .pull_stream_from(&storage, &PullTarget::Latest, &mut out)
+            .await
+            .unwrap();
+        assert_eq!(key, "project-db/dump-1.sql.zst");
+        assert_eq!(out.len() as u64, pushed.uploaded);
     }
 }
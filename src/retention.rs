@@ -0,0 +1,205 @@
+use crate::realms::RealmLifetime;
+use crate::s3::S3Object;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashSet;
+
+/// RetentionPlan splits realm files into the ones to keep and the ones to delete
+#[derive(Debug, Default)]
+pub struct RetentionPlan {
+    /// files to keep, newest first
+    pub keep: Vec<S3Object>,
+    /// files to delete, newest first
+    pub delete: Vec<S3Object>,
+}
+
+impl RetentionPlan {
+    /// computes the plan over files of the realm, the newest file is always kept
+    pub fn new(lifetime: &RealmLifetime, mut objects: Vec<S3Object>, now: DateTime<Utc>) -> Self {
+        objects.sort_by(|a, b| {
+            b.last_modified
+                .cmp(&a.last_modified)
+                .then_with(|| b.key.cmp(&a.key))
+        });
+        let cutoff = match lifetime.max_age {
+            0 => None,
+            days => Some(now - Duration::days(days as i64)),
+        };
+        let gfs = gfs_kept(lifetime, &objects);
+        // GFS rules alone define what is kept
+        let gfs_only = !gfs.is_empty() && lifetime.max_age == 0 && lifetime.max_files == 0;
+        let min_files = lifetime.min_files.max(1);
+        let mut plan = Self::default();
+        for (index, obj) in objects.into_iter().enumerate() {
+            let too_many = lifetime.max_files > 0 && index as u64 >= lifetime.max_files;
+            let too_old = cutoff.is_some_and(|cutoff| obj.last_modified < cutoff);
+            let kept = (index as u64) < min_files || gfs.contains(&index);
+            if !kept && (too_many || too_old || gfs_only) {
+                plan.delete.push(obj);
+            } else {
+                plan.keep.push(obj);
+            }
+        }
+        plan
+    }
+
+    /// total size of the files to delete
+    pub fn bytes_freed(&self) -> i64 {
+        self.delete.iter().map(|o| o.size).sum()
+    }
+}
+
+/// indexes of the files kept by GFS rules: the newest file of each of the last N periods.
+/// Files are expected to be sorted newest first, the set is empty when there are no rules
+fn gfs_kept(lifetime: &RealmLifetime, objects: &[S3Object]) -> HashSet<usize> {
+    let rules = [
+        (lifetime.keep_daily, "%Y-%m-%d"),
+        (lifetime.keep_weekly, "%G-W%V"),
+        (lifetime.keep_monthly, "%Y-%m"),
+        (lifetime.keep_yearly, "%Y"),
+    ];
+    let mut out = HashSet::new();
+    for (count, period_format) in rules {
+        let mut periods = 0;
+        let mut last_period = None;
+        for (index, obj) in objects.iter().enumerate() {
+            let period = obj.last_modified.format(period_format).to_string();
+            if last_period.as_ref() == Some(&period) {
+                continue;
+            }
+            if periods == count {
+                break;
+            }
+            out.insert(index);
+            periods += 1;
+            last_period = Some(period);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn objects(now: DateTime<Utc>, ages_in_days: &[i64]) -> Vec<S3Object> {
+        ages_in_days
+            .iter()
+            .map(|age| S3Object {
+                key: format!("db-{}.sql", age),
+                last_modified: now - Duration::days(*age),
+                size: 9,
+            })
+            .collect()
+    }
+
+    fn keys(list: &[S3Object]) -> Vec<&str> {
+        list.iter().map(|o| o.key.as_str()).collect()
+    }
+
+    #[test]
+    fn test_max_files_keeps_newest() {
+        let now = Utc::now();
+        let lifetime = RealmLifetime {
+            max_files: 2,
+            ..Default::default()
+        };
+        let plan = RetentionPlan::new(&lifetime, objects(now, &[3, 0, 2, 1]), now);
+        assert_eq!(keys(&plan.keep), vec!["db-0.sql", "db-1.sql"]);
+        assert_eq!(keys(&plan.delete), vec!["db-2.sql", "db-3.sql"]);
+        assert_eq!(plan.bytes_freed(), 18);
+    }
+
+    #[test]
+    fn test_max_age() {
+        let now = Utc::now();
+        let lifetime = RealmLifetime {
+            max_age: 7,
+            ..Default::default()
+        };
+        let plan = RetentionPlan::new(&lifetime, objects(now, &[1, 10, 6, 30]), now);
+        assert_eq!(keys(&plan.keep), vec!["db-1.sql", "db-6.sql"]);
+        assert_eq!(keys(&plan.delete), vec!["db-10.sql", "db-30.sql"]);
+
+        // the newest file is kept even when it is too old
+        let plan = RetentionPlan::new(&lifetime, objects(now, &[30, 10]), now);
+        assert_eq!(keys(&plan.keep), vec!["db-10.sql"]);
+        assert_eq!(keys(&plan.delete), vec!["db-30.sql"]);
+    }
+
+    #[test]
+    fn test_unlimited_and_empty() {
+        let now = Utc::now();
+        let lifetime = RealmLifetime {
+            ..Default::default()
+        };
+        let plan = RetentionPlan::new(&lifetime, objects(now, &[5, 100, 1000]), now);
+        assert_eq!(plan.keep.len(), 3);
+        assert!(plan.delete.is_empty());
+
+        let plan = RetentionPlan::new(&lifetime, vec![], now);
+        assert!(plan.keep.is_empty() && plan.delete.is_empty());
+    }
+
+    #[test]
+    fn test_min_files() {
+        let now = Utc::now();
+        let lifetime = RealmLifetime {
+            max_age: 7,
+            min_files: 2,
+            ..Default::default()
+        };
+        let plan = RetentionPlan::new(&lifetime, objects(now, &[10, 20, 30]), now);
+        assert_eq!(keys(&plan.keep), vec!["db-10.sql", "db-20.sql"]);
+        assert_eq!(keys(&plan.delete), vec!["db-30.sql"]);
+    }
+
+    #[test]
+    fn test_gfs() {
+        let now: DateTime<Utc> = "2024-03-31T12:00:00Z".parse().unwrap();
+        // daily backups since the new year, 2024-03-31 is Sunday
+        let first: DateTime<Utc> = "2024-01-01T03:00:00Z".parse().unwrap();
+        let objects: Vec<S3Object> = (0..91)
+            .map(|day| {
+                let last_modified = first + Duration::days(day);
+                S3Object {
+                    key: format!("db-{}.sql", last_modified.format("%Y-%m-%d")),
+                    last_modified,
+                    size: 9,
+                }
+            })
+            .collect();
+        let lifetime = RealmLifetime {
+            keep_daily: 3,
+            keep_weekly: 2,
+            keep_monthly: 3,
+            keep_yearly: 1,
+            ..Default::default()
+        };
+        let plan = RetentionPlan::new(&lifetime, objects.clone(), now);
+        assert_eq!(
+            keys(&plan.keep),
+            vec![
+                "db-2024-03-31.sql",
+                "db-2024-03-30.sql",
+                "db-2024-03-29.sql",
+                "db-2024-03-24.sql",
+                "db-2024-02-29.sql",
+                "db-2024-01-31.sql",
+            ]
+        );
+        assert_eq!(plan.delete.len(), 85);
+
+        // GFS rules keep older files beyond the limits
+        let lifetime = RealmLifetime {
+            max_age: 7,
+            keep_monthly: 3,
+            ..Default::default()
+        };
+        let plan = RetentionPlan::new(&lifetime, objects, now);
+        assert_eq!(plan.keep.len(), 9);
+        assert_eq!(
+            keys(&plan.keep)[7..],
+            ["db-2024-02-29.sql", "db-2024-01-31.sql"]
+        );
+    }
+}
@@ -1,17 +1,31 @@
+use crate::endpoints::metrics::S3_RETRIES;
+use crate::storage::{read_part, verified_reader, verified_rest, ByteReader, StorageBackend};
 use anyhow::Context;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use bytes::{Bytes, BytesMut};
-use futures::TryStreamExt;
+use futures::{future, stream, Future, TryStreamExt};
+use rand::Rng;
 
 use rusoto_core::request::HttpClient;
-use rusoto_core::{Client, Region};
-use rusoto_credential::StaticProvider;
-use rusoto_s3::{S3Client, S3};
-use std::path::PathBuf;
+use rusoto_core::{Client, Region, RusotoError};
+use rusoto_credential::{
+    AutoRefreshingProvider, AwsCredentials, ContainerProvider, CredentialsError,
+    EnvironmentProvider, InstanceMetadataProvider, ProfileProvider, ProvideAwsCredentials,
+    StaticProvider,
+};
+use rusoto_s3::{
+    CompletedMultipartUpload, CompletedPart, GetObjectError, HeadObjectError, S3Client, S3,
+};
+use rusoto_sts::WebIdentityProvider;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::time::Duration;
 
 use futures::stream::Stream;
-use tokio::io::AsyncRead;
-use tokio_util::codec;
+use tokio::io::AsyncReadExt;
 use tracing::*;
 
 use serde::Deserialize;
@@ -43,11 +57,345 @@ pub enum S3Region {
     Endpoint(String),
 }
 
+impl S3Region {
+    /// region of the client, custom one for the endpoint
+    pub fn to_region(&self) -> anyhow::Result<Region> {
+        match self {
+            Self::Region(r) => {
+                Region::from_str(r).with_context(|| format!("invalid s3 region {}", r))
+            }
+            Self::Endpoint(url) => {
+                reqwest::Url::parse(url).with_context(|| format!("invalid s3 endpoint {}", url))?;
+                Ok(Region::Custom {
+                    name: "custom".to_string(),
+                    endpoint: url.to_string(),
+                })
+            }
+        }
+    }
+}
+
+/// source of the S3 credentials
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialsSource {
+    /// `access_key` and `secret_access_key` of the realm
+    Static,
+    /// AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN variables
+    Environment,
+    /// profile of the shared credentials file, ~/.aws/credentials by default
+    Profile,
+    /// web identity token of the Kubernetes service account (IRSA), exchanged for the role
+    /// credentials, as configured by AWS_WEB_IDENTITY_TOKEN_FILE and AWS_ROLE_ARN
+    WebIdentity,
+    /// ECS container credentials
+    Container,
+    /// EC2 instance metadata
+    Instance,
+    /// the first of environment, profile, web identity, container and instance sources
+    /// which has the credentials
+    Chain,
+}
+
+/// S3 credentials of the realm
+#[derive(Clone, Default, Deserialize)]
+pub struct S3Credentials {
+    /// source of the credentials, "static" if the keys are given and "chain" otherwise
+    #[serde(default)]
+    pub credentials: Option<CredentialsSource>,
+    /// S3 access key
+    #[serde(default)]
+    pub access_key: Option<String>,
+    /// S3 secret key
+    #[serde(default)]
+    pub secret_access_key: Option<String>,
+    /// profile of the shared credentials file, AWS_PROFILE or "default" if missing
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+impl std::fmt::Debug for S3Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Credentials")
+            .field("credentials", &self.source())
+            .field("access_key", &self.access_key)
+            .field("profile", &self.profile)
+            .finish()
+    }
+}
+
+type BoxedProvider = Box<dyn ProvideAwsCredentials + Send + Sync>;
+
+impl S3Credentials {
+    /// config keys of the credentials
+    pub const FIELDS: &'static [&'static str] =
+        &["credentials", "access_key", "secret_access_key", "profile"];
+
+    /// configured source of the credentials
+    pub fn source(&self) -> CredentialsSource {
+        match self.credentials {
+            Some(source) => source,
+            None if self.access_key.is_some() || self.secret_access_key.is_some() => {
+                CredentialsSource::Static
+            }
+            None => CredentialsSource::Chain,
+        }
+    }
+
+    /// checks that the credentials of the configured source can be looked up
+    pub fn check(&self) -> anyhow::Result<()> {
+        let empty = |key: &Option<String>| key.as_deref().is_none_or(str::is_empty);
+        if self.source() == CredentialsSource::Static
+            && (empty(&self.access_key) || empty(&self.secret_access_key))
+        {
+            anyhow::bail!("static credentials require access_key and secret_access_key");
+        }
+        self.provider().map(|_| ())
+    }
+
+    fn provider(&self) -> anyhow::Result<CredentialsChain> {
+        use CredentialsSource::*;
+        match self.source() {
+            Chain => {
+                let mut providers = vec![];
+                for source in [Environment, Profile, WebIdentity, Container, Instance] {
+                    match self.provider_of(source) {
+                        Ok(provider) => providers.push(provider),
+                        Err(e) => debug!("skipping {:?} credentials: {:#}", source, e),
+                    }
+                }
+                Ok(CredentialsChain(providers))
+            }
+            source => Ok(CredentialsChain(vec![self.provider_of(source)?])),
+        }
+    }
+
+    fn provider_of(&self, source: CredentialsSource) -> anyhow::Result<BoxedProvider> {
+        Ok(match source {
+            CredentialsSource::Static => match (&self.access_key, &self.secret_access_key) {
+                (Some(access_key), Some(secret_access_key)) => Box::new(
+                    StaticProvider::new_minimal(access_key.clone(), secret_access_key.clone()),
+                ),
+                _ => anyhow::bail!("static credentials require access_key and secret_access_key"),
+            },
+            CredentialsSource::Environment => Box::new(EnvironmentProvider::default()),
+            CredentialsSource::Profile => Box::new(
+                match &self.profile {
+                    Some(profile) => ProfileProvider::with_default_credentials(profile),
+                    None => ProfileProvider::new(),
+                }
+                .context("failed to locate shared credentials file")?,
+            ),
+            CredentialsSource::WebIdentity => Box::new(WebIdentityProvider::from_k8s_env()),
+            CredentialsSource::Container => Box::new(ContainerProvider::new()),
+            CredentialsSource::Instance => Box::new(InstanceMetadataProvider::new()),
+            CredentialsSource::Chain => anyhow::bail!("chain can not be nested"),
+        })
+    }
+}
+
+/// CredentialsChain takes the credentials from the first provider which has them
+struct CredentialsChain(Vec<BoxedProvider>);
+
+#[async_trait]
+impl ProvideAwsCredentials for CredentialsChain {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let mut errors = vec![];
+        for provider in &self.0 {
+            match provider.credentials().await {
+                Ok(credentials) => return Ok(credentials),
+                Err(e) => errors.push(e.message),
+            }
+        }
+        Err(CredentialsError::new(format!(
+            "no S3 credentials found: {}",
+            errors.join("; ")
+        )))
+    }
+}
+
+const MIB: u64 = 1024 * 1024;
+/// S3 refuses smaller parts, except the last one
+const MIN_PART_SIZE: u64 = 5 * MIB;
+/// S3 limit of parts in a single upload
+const MAX_PARTS: u64 = 10_000;
+/// S3 limit of the part size
+const MAX_PART_SIZE: u64 = 5 * 1024 * MIB;
+/// parts of the same size, when the upload size is unknown. Doubling the size every
+/// 1000 parts fits 5 TiB, the S3 limit of the object size, into 10000 parts of 16 MiB and more,
+/// and almost as much into parts of 5 MiB
+const GROWTH_PARTS: u64 = 1000;
+/// user metadata with SHA-256 of the object, verified on download
+const SHA256_METADATA: &str = "sha256";
+/// extension of the hidden object with SHA-256 of the multipart upload, which is known only
+/// after the parts are read. These objects are not listed as realm files
+const CHECKSUM_EXTENSION: &str = ".sha256";
+
+/// multipart upload settings of S3 realm
+#[derive(Debug, Clone, Deserialize)]
+pub struct MultipartConfig {
+    /// size of the upload part in MiB, files smaller than a part are sent with a single request
+    #[serde(default = "default_part_size_mb")]
+    pub part_size_mb: u64,
+    /// number of parts uploaded at the same time
+    #[serde(default = "default_upload_parallelism")]
+    pub upload_parallelism: usize,
+}
+
+fn default_part_size_mb() -> u64 {
+    16
+}
+
+fn default_upload_parallelism() -> usize {
+    4
+}
+
+impl Default for MultipartConfig {
+    fn default() -> Self {
+        Self {
+            part_size_mb: default_part_size_mb(),
+            upload_parallelism: default_upload_parallelism(),
+        }
+    }
+}
+
+impl MultipartConfig {
+    /// config keys of the settings
+    pub const FIELDS: &'static [&'static str] = &["part_size_mb", "upload_parallelism"];
+
+    /// problems of the settings, as the field and the message
+    pub fn problems(&self) -> Vec<(&'static str, String)> {
+        let mut out = vec![];
+        if self.upload_parallelism == 0 {
+            out.push(("upload_parallelism", "must be at least 1".to_string()));
+        }
+        out
+    }
+
+    /// size of the first part in bytes, grown when needed to fit the known upload size
+    /// into S3 limits. Parts of unknown size uploads are grown later, see `grown_part_size`
+    fn part_size(&self, size: Option<u64>) -> u64 {
+        let part_size = (self.part_size_mb * MIB).max(MIN_PART_SIZE);
+        match size {
+            Some(size) => part_size.max(size.div_ceil(MAX_PARTS)),
+            None => part_size,
+        }
+    }
+}
+
+/// retry policy of S3 requests, with exponential backoff between the attempts
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetryConfig {
+    /// number of attempts of each request, including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// delay before the first retry in milliseconds, doubled with every next one
+    #[serde(default = "default_base_delay_ms")]
+    pub base_delay_ms: u64,
+    /// upper limit of the delay in milliseconds
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// whether the delay is randomized between zero and its value,
+    /// so that parallel requests do not retry at once
+    #[serde(default = "default_true")]
+    pub jitter: bool,
+    /// HTTP status codes of the responses to retry
+    #[serde(default = "default_retry_statuses")]
+    pub retry_statuses: Vec<u16>,
+    /// whether connection errors and timeouts are retried
+    #[serde(default = "default_true")]
+    pub retry_network_errors: bool,
+}
+
+fn default_max_attempts() -> u32 {
+    4
+}
+
+fn default_base_delay_ms() -> u64 {
+    200
+}
+
+fn default_max_delay_ms() -> u64 {
+    10_000
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_retry_statuses() -> Vec<u16> {
+    // throttling (503 SlowDown included) and server errors
+    vec![429, 500, 502, 503, 504]
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            base_delay_ms: default_base_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+            jitter: true,
+            retry_statuses: default_retry_statuses(),
+            retry_network_errors: true,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// config keys of the policy
+    pub const FIELDS: &'static [&'static str] = &[
+        "max_attempts",
+        "base_delay_ms",
+        "max_delay_ms",
+        "jitter",
+        "retry_statuses",
+        "retry_network_errors",
+    ];
+
+    /// whether the failed request is worth another attempt
+    fn is_retryable<E>(&self, e: &RusotoError<E>) -> bool {
+        match e {
+            RusotoError::HttpDispatch(_) => self.retry_network_errors,
+            RusotoError::Unknown(res) => self.retry_statuses.contains(&res.status.as_u16()),
+            _ => false,
+        }
+    }
+
+    /// problems of the settings, as the field and the message
+    pub fn problems(&self) -> Vec<(&'static str, String)> {
+        let mut out = vec![];
+        if self.max_attempts == 0 {
+            out.push(("retry.max_attempts", "must be at least 1".to_string()));
+        }
+        if self.base_delay_ms > self.max_delay_ms {
+            out.push((
+                "retry.base_delay_ms",
+                format!("is greater than max_delay_ms {}", self.max_delay_ms),
+            ));
+        }
+        out
+    }
+
+    /// delay after the failed attempt, starting from 1
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay_ms
+            .saturating_mul(1 << attempt.saturating_sub(1).min(30))
+            .min(self.max_delay_ms);
+        match self.jitter {
+            true => Duration::from_millis(rand::thread_rng().gen_range(0..=delay)),
+            false => Duration::from_millis(delay),
+        }
+    }
+}
+
 /// Bucket embeds S3 client object and bucket name
 #[derive(Clone)]
 pub struct Bucket {
     pub client: S3Client,
     pub bucket: String,
+    pub multipart: MultipartConfig,
+    pub retry: RetryConfig,
 }
 impl std::fmt::Debug for Bucket {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -57,46 +405,139 @@ impl std::fmt::Debug for Bucket {
     }
 }
 
-fn into_bytes_stream<R>(r: R) -> impl Stream<Item = tokio::io::Result<Bytes>>
-where
-    R: AsyncRead,
-{
-    codec::FramedRead::new(r, codec::BytesCodec::new()).map_ok(|bytes| bytes.freeze())
+/// base64 MD5 of the body, for S3 to verify it on upload
+fn content_md5(contents: &[u8]) -> String {
+    BASE64.encode(md5::Md5::digest(contents))
+}
+
+fn sha256_metadata(sha256: String) -> HashMap<String, String> {
+    HashMap::from([(SHA256_METADATA.to_string(), sha256)])
+}
+
+/// key of the object with SHA-256 of the multipart upload, hidden as the partial uploads,
+/// so that the user's own `name.sha256` files are left alone
+fn checksum_key(filename: &str) -> String {
+    let (dir, name) = match filename.rsplit_once('/') {
+        Some((dir, name)) => (&filename[..dir.len() + 1], name),
+        None => ("", filename),
+    };
+    format!("{}.{}{}", dir, name, CHECKSUM_EXTENSION)
+}
+
+/// whether the key belongs to the object with SHA-256 of the multipart upload
+fn is_checksum(key: &str) -> bool {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    name.starts_with('.') && name.ends_with(CHECKSUM_EXTENSION)
+}
+
+/// body of the downloaded object along with its SHA-256, if it was stored on upload
+fn body_of(object: rusoto_s3::GetObjectOutput) -> anyhow::Result<(ByteReader, Option<String>)> {
+    let stream = match object.body {
+        Some(x) => x,
+        None => return Err(anyhow::Error::msg("stream download error")),
+    };
+    let sha256 = object.metadata.and_then(|mut m| m.remove(SHA256_METADATA));
+    Ok((Box::pin(stream.into_async_read()), sha256))
+}
+
+fn byte_stream_of(contents: Bytes) -> rusoto_core::ByteStream {
+    let size = contents.len();
+    rusoto_core::ByteStream::new_with_size(stream::once(future::ready(Ok(contents))), size)
+}
+
+/// size of the part with the given number, doubled every `GROWTH_PARTS` parts
+fn grown_part_size(part_size: u64, number: i64) -> u64 {
+    let doublings = (number as u64 - 1) / GROWTH_PARTS;
+    part_size
+        .saturating_mul(1 << doublings.min(32))
+        .min(MAX_PART_SIZE)
+}
+
+/// splits the rest of the body into numbered parts, following the already read first part.
+/// With `grow` the parts are growing, so that the body of unknown size fits into S3 limits
+fn parts_of(
+    first: Bytes,
+    body: ByteReader,
+    part_size: u64,
+    grow: bool,
+) -> impl Stream<Item = anyhow::Result<(i64, Bytes)>> {
+    stream::try_unfold(
+        (Some(first), body, 1),
+        move |(first, mut body, number)| async move {
+            let size = match grow {
+                true => grown_part_size(part_size, number),
+                false => part_size,
+            };
+            let part = match first {
+                Some(part) => part,
+                None => read_part(&mut body, size)
+                    .await
+                    .context("failed to read upload stream")?,
+            };
+            if part.is_empty() {
+                return Ok(None);
+            }
+            if number as u64 > MAX_PARTS {
+                anyhow::bail!("upload stream exceeds {} parts of S3 upload", MAX_PARTS);
+            }
+            Ok(Some(((number, part), (None, body, number + 1))))
+        },
+    )
 }
 
 impl Bucket {
     /// creates new s3 bucket object
     pub fn new(
-        s3_access_key: &str,
-        s3_secret_access_key: &str,
+        credentials: &S3Credentials,
         s3_bucket: &str,
         s3_region: &S3Region,
+        multipart: &MultipartConfig,
+        retry: &RetryConfig,
     ) -> anyhow::Result<Self> {
         tracing::debug!("accessing s3 bucket {} in {:?}", s3_bucket, s3_region);
 
-        let region = match s3_region {
-            S3Region::Region(r) => Region::from_str(r).context("invalid s3 region")?,
-            S3Region::Endpoint(url) => Region::Custom {
-                name: "custom".to_string(),
-                endpoint: url.to_string(),
-            },
-        };
-        let aws_provider: StaticProvider = StaticProvider::new(
-            s3_access_key.to_string(),
-            s3_secret_access_key.to_string(),
-            None,
-            None,
-        );
-        let http_client =
-            match HttpClient::new().with_context(|| format!("Failed to create AWS HTTP client")) {
-                Err(e) => return Err(e),
-                Ok(x) => x,
-            };
+        let region = s3_region.to_region()?;
+        // temporary credentials are refreshed before they expire
+        let aws_provider = AutoRefreshingProvider::new(credentials.provider()?)
+            .context("invalid S3 credentials")?;
+        let http_client = HttpClient::new().context("Failed to create AWS HTTP client")?;
         let client = Client::new_with(aws_provider, http_client);
-        return Ok(Self {
+        Ok(Self {
             client: S3Client::new_with_client(client, region),
             bucket: s3_bucket.to_string(),
-        });
+            multipart: multipart.clone(),
+            retry: retry.clone(),
+        })
+    }
+
+    /// runs the request, retrying it on transient errors with the retry policy of the bucket
+    async fn retried<T, E, F, Fut>(
+        &self,
+        operation: &str,
+        mut request: F,
+    ) -> Result<T, RusotoError<E>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, RusotoError<E>>>,
+        E: std::error::Error + 'static,
+    {
+        let attempts = self.retry.max_attempts;
+        let mut attempt = 1;
+        loop {
+            match request().await {
+                Err(e) if attempt < attempts && self.retry.is_retryable(&e) => {
+                    let delay = self.retry.delay(attempt);
+                    warn!(
+                        "{} failed (attempt {} of {}), retrying in {:?}: {}",
+                        operation, attempt, attempts, delay, e
+                    );
+                    S3_RETRIES.with_label_values(&[operation]).inc();
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
     }
 
     #[instrument(ret, level = "info")]
@@ -105,57 +546,198 @@ impl Bucket {
         if length == 0 {
             return Ok(0);
         }
-        let put_req = rusoto_s3::PutObjectRequest {
+        let contents = Bytes::from(contents);
+        self.retried("put_object", || {
+            let put_req = rusoto_s3::PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: filename.to_string(),
+                content_length: Some(length as i64),
+                body: Some(byte_stream_of(contents.clone())),
+                ..Default::default()
+            };
+            self.client.put_object(put_req)
+        })
+        .await
+        .context("failed to put object")?;
+        Ok(length)
+    }
+
+    /// uploads the body with a single request, along with its checksums
+    async fn put_object(&self, filename: &str, contents: Bytes) -> anyhow::Result<u64> {
+        let length = contents.len() as u64;
+        if length == 0 {
+            return Ok(0);
+        }
+        let md5 = content_md5(&contents);
+        let sha256 = format!("{:x}", Sha256::digest(&contents));
+        self.retried("put_object", || {
+            let put_req = rusoto_s3::PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: filename.to_string(),
+                content_length: Some(length as i64),
+                content_md5: Some(md5.clone()),
+                metadata: Some(sha256_metadata(sha256.clone())),
+                body: Some(byte_stream_of(contents.clone())),
+                ..Default::default()
+            };
+            self.client.put_object(put_req)
+        })
+        .await
+        .context("failed to put object")?;
+        Ok(length)
+    }
+
+    /// uploads the parts in parallel, aborting the upload on failure.
+    /// SHA-256 is only known in the end, so it is stored in the separate object
+    async fn put_multipart(
+        &self,
+        filename: &str,
+        parts: impl Stream<Item = anyhow::Result<(i64, Bytes)>>,
+    ) -> anyhow::Result<u64> {
+        let create_req = rusoto_s3::CreateMultipartUploadRequest {
             bucket: self.bucket.clone(),
             key: filename.to_string(),
-            content_length: Some(length as i64),
-            body: Some(contents.into_bytes().into()),
             ..Default::default()
         };
-        let _ = self
-            .client
-            .put_object(put_req)
+        let upload_id = self
+            .retried("create_multipart_upload", || {
+                self.client.create_multipart_upload(create_req.clone())
+            })
             .await
-            .context("failed to put object")?;
-        Ok(length)
+            .context("failed to create multipart upload")?
+            .upload_id
+            .context("missing multipart upload id")?;
+        let res = async {
+            let mut hasher = Sha256::new();
+            // parts are read in order, so they are hashed before the parallel upload
+            let mut completed: Vec<(CompletedPart, u64)> = parts
+                .map_ok(|(number, part)| {
+                    hasher.update(&part);
+                    self.upload_part(filename, &upload_id, number, part)
+                })
+                .try_buffer_unordered(self.multipart.upload_parallelism.max(1))
+                .try_collect()
+                .await?;
+            // the checksum is stored before the object appears, the upload is aborted without it
+            let checksum = checksum_key(filename);
+            self.put_object(&checksum, format!("{:x}", hasher.finalize()).into())
+                .await
+                .with_context(|| format!("failed to store checksum of {}", filename))?;
+            completed.sort_by_key(|(part, _)| part.part_number);
+            let length = completed.iter().map(|(_, length)| length).sum();
+            let complete_req = rusoto_s3::CompleteMultipartUploadRequest {
+                bucket: self.bucket.clone(),
+                key: filename.to_string(),
+                upload_id: upload_id.clone(),
+                multipart_upload: Some(CompletedMultipartUpload {
+                    parts: Some(completed.into_iter().map(|(part, _)| part).collect()),
+                }),
+                ..Default::default()
+            };
+            let res = self
+                .retried("complete_multipart_upload", || {
+                    self.client.complete_multipart_upload(complete_req.clone())
+                })
+                .await
+                .context("failed to complete multipart upload");
+            if res.is_err() {
+                if let Err(e) = self.delete_object(&checksum).await {
+                    error!("failed to delete {}: {:#}", checksum, e);
+                }
+            }
+            res.map(|_| length)
+        }
+        .await;
+        if res.is_err() {
+            let abort_req = rusoto_s3::AbortMultipartUploadRequest {
+                bucket: self.bucket.clone(),
+                key: filename.to_string(),
+                upload_id: upload_id.clone(),
+                ..Default::default()
+            };
+            let res = self
+                .retried("abort_multipart_upload", || {
+                    self.client.abort_multipart_upload(abort_req.clone())
+                })
+                .await;
+            if let Err(e) = res {
+                error!("failed to abort multipart upload {}: {}", upload_id, e);
+            }
+        }
+        res
     }
 
-    #[instrument(ret, level = "info")]
-    pub async fn put_file(&self, filename: &str, local_filename: &PathBuf) -> anyhow::Result<u64> {
-        let tokio_file = tokio::fs::File::open(local_filename)
-            .await
-            .context("failed to open local file")?;
-        let file_size = tokio_file.metadata().await?.len();
-        if file_size == 0 {
-            return Ok(0);
-        }
-        let put_req = rusoto_s3::PutObjectRequest {
+    /// SHA-256 of the multipart upload, None for the files uploaded without it
+    async fn get_checksum(&self, filename: &str) -> anyhow::Result<Option<String>> {
+        let get_req = rusoto_s3::GetObjectRequest {
             bucket: self.bucket.clone(),
-            key: filename.to_string(),
-            content_length: Some(file_size as i64),
-            body: Some(rusoto_core::ByteStream::new(into_bytes_stream(tokio_file))),
+            key: checksum_key(filename),
             ..Default::default()
         };
-        self.client
-            .put_object(put_req)
+        let object = match self
+            .retried("get_object", || self.client.get_object(get_req.clone()))
+            .await
+        {
+            Ok(x) => x,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+            Err(RusotoError::Unknown(res)) if res.status == 404 => return Ok(None),
+            Err(e) => return Err(anyhow::Error::new(e).context("failed to get checksum")),
+        };
+        let (mut body, _) = body_of(object)?;
+        let mut sha256 = String::new();
+        body.read_to_string(&mut sha256)
             .await
-            .context("failed to put object")?;
-        Ok(file_size)
+            .context("failed to read checksum")?;
+        Ok(Some(sha256.trim().to_string()))
     }
 
-    #[instrument(ret, level = "warn")]
-    pub async fn delete_file(&self, filename: &str) -> anyhow::Result<()> {
+    /// deletes the single object, the realm file without its checksum
+    async fn delete_object(&self, filename: &str) -> anyhow::Result<()> {
         let del_req = rusoto_s3::DeleteObjectRequest {
             bucket: self.bucket.clone(),
             key: filename.to_string(),
             ..Default::default()
         };
-        self.client
-            .delete_object(del_req)
-            .await
-            .context("failed to delete object")?;
+        self.retried("delete_object", || {
+            self.client.delete_object(del_req.clone())
+        })
+        .await
+        .context("failed to delete object")?;
         Ok(())
     }
+
+    /// uploads single part, retrying it with the retry policy of the bucket
+    async fn upload_part(
+        &self,
+        filename: &str,
+        upload_id: &str,
+        number: i64,
+        part: Bytes,
+    ) -> anyhow::Result<(CompletedPart, u64)> {
+        let md5 = content_md5(&part);
+        let output = self
+            .retried("upload_part", || {
+                let part_req = rusoto_s3::UploadPartRequest {
+                    bucket: self.bucket.clone(),
+                    key: filename.to_string(),
+                    upload_id: upload_id.to_string(),
+                    part_number: number,
+                    content_length: Some(part.len() as i64),
+                    content_md5: Some(md5.clone()),
+                    body: Some(byte_stream_of(part.clone())),
+                    ..Default::default()
+                };
+                self.client.upload_part(part_req)
+            })
+            .await
+            .with_context(|| format!("failed to upload part {} of {}", number, filename))?;
+        let completed = CompletedPart {
+            e_tag: output.e_tag,
+            part_number: Some(number),
+        };
+        Ok((completed, part.len() as u64))
+    }
+
     /// Get remote S3 file as string
     #[instrument(level = "info")]
     pub async fn get_str(&self, filename: &str) -> anyhow::Result<String> {
@@ -164,7 +746,10 @@ impl Bucket {
             key: filename.to_string(),
             ..Default::default()
         };
-        let file = match self.client.get_object(get_req).await {
+        let file = match self
+            .retried("get_object", || self.client.get_object(get_req.clone()))
+            .await
+        {
             Err(e) => return Err(anyhow::Error::new(e)),
             Ok(x) => x,
         };
@@ -182,54 +767,597 @@ impl Bucket {
             Err(e) => return Err(anyhow::Error::new(e)),
         }
     }
+}
+
+#[async_trait]
+impl StorageBackend for Bucket {
+    #[instrument(ret, level = "info")]
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<S3Object>> {
+        let mut out = vec![];
+        let now = chrono::Utc::now();
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let list_req = rusoto_s3::ListObjectsV2Request {
+                bucket: self.bucket.clone(),
+                prefix: Some(prefix.to_string()),
+                continuation_token: continuation_token.take(),
+                ..Default::default()
+            };
+            let output = self
+                .retried("list_objects_v2", || {
+                    self.client.list_objects_v2(list_req.clone())
+                })
+                .await
+                .context("failed to list objects")?;
+            for o in output.contents.unwrap_or_default() {
+                if is_checksum(o.key.as_deref().unwrap_or_default()) {
+                    continue;
+                }
+                out.push(S3Object {
+                    key: o.key.unwrap_or_default(),
+                    last_modified: o.last_modified.unwrap_or_default().parse().unwrap_or(now),
+                    size: o.size.unwrap_or_default(),
+                });
+            }
+            continuation_token = match output.is_truncated {
+                Some(true) => output.next_continuation_token,
+                _ => None,
+            };
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+        // sort out by last modified
+        out.sort_by_key(|o| std::cmp::Reverse(o.last_modified));
+        Ok(out)
+    }
+
+    #[instrument(ret, level = "info", skip(body))]
+    async fn put(
+        &self,
+        filename: &str,
+        mut body: ByteReader,
+        size: Option<u64>,
+    ) -> anyhow::Result<u64> {
+        let part_size = self.multipart.part_size(size);
+        let first = read_part(&mut body, part_size)
+            .await
+            .context("failed to read upload stream")?;
+        if (first.len() as u64) < part_size {
+            // the whole body fits into a single part
+            return self.put_object(filename, first).await;
+        }
+        let parts = parts_of(first, body, part_size, size.is_none());
+        self.put_multipart(filename, parts).await
+    }
 
-    /// Save remote S3 file to local file
-    #[instrument(level = "info", ret)]
-    pub async fn get_file(&self, filename: &str, local_filename: &PathBuf) -> anyhow::Result<u64> {
+    #[instrument(level = "info")]
+    async fn get(&self, filename: &str) -> anyhow::Result<ByteReader> {
         let get_req = rusoto_s3::GetObjectRequest {
             bucket: self.bucket.clone(),
             key: filename.to_string(),
             ..Default::default()
         };
-        let object = match self.client.get_object(get_req).await {
+        let object = match self
+            .retried("get_object", || self.client.get_object(get_req.clone()))
+            .await
+        {
             Err(e) => return Err(anyhow::Error::new(e)),
             Ok(x) => x,
         };
-        let stream = match object.body {
-            Some(x) => x,
-            None => return Err(anyhow::Error::msg("stream download error")),
+        let (body, sha256) = body_of(object)?;
+        let sha256 = match sha256 {
+            Some(sha256) => Some(sha256),
+            None => self.get_checksum(filename).await?,
         };
-        // save stream to local file
-        let mut body = stream.into_async_read();
-        let mut file = tokio::fs::File::create(local_filename).await?;
-        let out = tokio::io::copy(&mut body, &mut file).await?;
-        Ok(out)
+        // files uploaded before checksums were introduced have none
+        match sha256 {
+            Some(sha256) => Ok(verified_reader(body, &sha256)),
+            None => Ok(body),
+        }
+    }
+
+    #[instrument(level = "info", skip(prefix))]
+    async fn get_range(
+        &self,
+        filename: &str,
+        etag: &str,
+        offset: u64,
+        prefix: ByteReader,
+    ) -> anyhow::Result<Option<ByteReader>> {
+        let get_req = rusoto_s3::GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: filename.to_string(),
+            range: Some(format!("bytes={}-", offset)),
+            if_match: Some(etag.to_string()),
+            ..Default::default()
+        };
+        let object = match self
+            .retried("get_object", || self.client.get_object(get_req.clone()))
+            .await
+        {
+            Ok(x) => x,
+            // the object was overwritten, or the range is beyond its end
+            Err(RusotoError::Unknown(res)) if res.status == 412 || res.status == 416 => {
+                return Ok(None)
+            }
+            Err(e) => return Err(anyhow::Error::new(e).context("failed to get object range")),
+        };
+        let (body, sha256) = body_of(object)?;
+        let sha256 = match sha256 {
+            Some(sha256) => Some(sha256),
+            None => self.get_checksum(filename).await?,
+        };
+        match sha256 {
+            Some(sha256) => Ok(Some(verified_rest(prefix, body, &sha256).await?)),
+            None => Ok(Some(body)),
+        }
+    }
+
+    #[instrument(ret, level = "warn")]
+    async fn delete(&self, filename: &str) -> anyhow::Result<()> {
+        self.delete_object(filename).await?;
+        // the checksum of the multipart upload, deleting the missing one succeeds
+        self.delete_object(&checksum_key(filename)).await
     }
 
     #[instrument(ret, level = "info")]
-    pub async fn list(&self, prefix: &str) -> anyhow::Result<Vec<S3Object>> {
-        let list_req = rusoto_s3::ListObjectsV2Request {
+    async fn etag(&self, filename: &str) -> anyhow::Result<Option<String>> {
+        let head_req = rusoto_s3::HeadObjectRequest {
             bucket: self.bucket.clone(),
-            prefix: Some(prefix.to_string()),
+            key: filename.to_string(),
             ..Default::default()
         };
         let output = self
-            .client
-            .list_objects_v2(list_req)
+            .retried("head_object", || self.client.head_object(head_req.clone()))
             .await
-            .context("failed to read object")?;
-        // println!("{:#?}", output);
-        let mut out = vec![];
+            .context("failed to head object")?;
+        Ok(output.e_tag)
+    }
+
+    #[instrument(ret, level = "info")]
+    async fn head(&self, filename: &str) -> anyhow::Result<Option<S3Object>> {
+        let head_req = rusoto_s3::HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: filename.to_string(),
+            ..Default::default()
+        };
+        let output = match self
+            .retried("head_object", || self.client.head_object(head_req.clone()))
+            .await
+        {
+            Ok(x) => x,
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => return Ok(None),
+            Err(RusotoError::Unknown(res)) if res.status == 404 => return Ok(None),
+            Err(e) => return Err(anyhow::Error::new(e).context("failed to head object")),
+        };
         let now = chrono::Utc::now();
-        for o in output.contents.unwrap_or_default() {
-            out.push(S3Object {
-                key: o.key.unwrap_or_default(),
-                last_modified: o.last_modified.unwrap_or_default().parse().unwrap_or(now),
-                size: o.size.unwrap_or_default(),
-            });
+        Ok(Some(S3Object {
+            key: filename.to_string(),
+            last_modified: output
+                .last_modified
+                .and_then(|s| chrono::DateTime::parse_from_rfc2822(&s).ok())
+                .map(|d| d.into())
+                .unwrap_or(now),
+            size: output.content_length.unwrap_or_default(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::signature::SignedRequest;
+    use rusoto_core::DispatchSignedRequest;
+    use rusoto_mock::{
+        MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher,
+    };
+
+    /// bucket "backups", which requests are answered by the dispatcher
+    fn mock_bucket(
+        dispatcher: impl DispatchSignedRequest + Send + Sync + 'static,
+        retry: RetryConfig,
+    ) -> Bucket {
+        Bucket {
+            client: S3Client::new_with(dispatcher, MockCredentialsProvider, Region::UsEast1),
+            bucket: "backups".to_string(),
+            multipart: MultipartConfig::default(),
+            retry,
         }
-        // sort out by last modified
-        out.sort_by(|a, b| b.last_modified.cmp(&a.last_modified));
-        Ok(out)
+    }
+
+    #[test]
+    fn test_part_size() {
+        let config = MultipartConfig::default();
+        assert_eq!(config.part_size(None), 16 * MIB);
+        assert_eq!(config.part_size(Some(100 * MIB)), 16 * MIB);
+        // 10000 parts of 16 MiB are not enough for 200 GiB
+        assert_eq!(
+            config.part_size(Some(200 * 1024 * MIB)),
+            (200 * 1024 * MIB).div_ceil(MAX_PARTS)
+        );
+        let small = MultipartConfig {
+            part_size_mb: 1,
+            ..Default::default()
+        };
+        assert_eq!(small.part_size(None), MIN_PART_SIZE);
+
+        // parts of unknown size uploads grow to fit the largest S3 object
+        assert_eq!(grown_part_size(MIN_PART_SIZE, 1000), MIN_PART_SIZE);
+        assert_eq!(grown_part_size(MIN_PART_SIZE, 1001), 2 * MIN_PART_SIZE);
+        assert_eq!(
+            grown_part_size(MIN_PART_SIZE, MAX_PARTS as i64),
+            512 * MIN_PART_SIZE
+        );
+        assert_eq!(grown_part_size(1024 * MIB, MAX_PARTS as i64), MAX_PART_SIZE);
+        let total: u64 = (1..=MAX_PARTS as i64)
+            .map(|number| grown_part_size(config.part_size(None), number))
+            .sum();
+        assert!(total >= 5 * 1024 * 1024 * MIB, "{}", total);
+    }
+
+    #[tokio::test]
+    async fn test_credentials() {
+        let inline = S3Credentials {
+            access_key: Some("AKID".to_string()),
+            secret_access_key: Some("secret".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(inline.source(), CredentialsSource::Static);
+        let credentials = inline.provider().unwrap().credentials().await.unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "AKID");
+        assert!(!format!("{:?}", inline).contains("secret"));
+        assert_eq!(S3Credentials::default().source(), CredentialsSource::Chain);
+        let incomplete = S3Credentials {
+            credentials: Some(CredentialsSource::Static),
+            ..Default::default()
+        };
+        assert!(incomplete.provider().is_err());
+
+        // the first provider with the credentials is used
+        let chain = CredentialsChain(vec![
+            Box::new(EnvironmentProvider::with_prefix("BACKUP_TEST_MISSING")),
+            Box::new(StaticProvider::new_minimal(
+                "AKID".to_string(),
+                "secret".to_string(),
+            )),
+        ]);
+        let credentials = chain.credentials().await.unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "AKID");
+        let chain = CredentialsChain(vec![Box::new(EnvironmentProvider::with_prefix(
+            "BACKUP_TEST_MISSING",
+        ))]);
+        let err = chain.credentials().await.unwrap_err();
+        assert!(err.message.contains("BACKUP_TEST_MISSING"), "{}", err);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = RetryConfig {
+            jitter: false,
+            max_delay_ms: 1000,
+            ..Default::default()
+        };
+        let delays: Vec<u64> = (1..=5).map(|a| retry.delay(a).as_millis() as u64).collect();
+        assert_eq!(delays, vec![200, 400, 800, 1000, 1000]);
+        let retry = RetryConfig::default();
+        assert!((1..=5).all(|a| retry.delay(a) <= Duration::from_millis(3200)));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let bucket = |statuses: &[u16]| {
+            let dispatcher = MultipleMockRequestDispatcher::new(
+                statuses
+                    .iter()
+                    .map(|status| MockRequestDispatcher::with_status(*status))
+                    .collect::<Vec<_>>(),
+            );
+            let retry = RetryConfig {
+                base_delay_ms: 1,
+                ..Default::default()
+            };
+            mock_bucket(dispatcher, retry)
+        };
+        let retries = || S3_RETRIES.with_label_values(&["delete_object"]).get();
+        let before = retries();
+        // throttled twice, then the object and its checksum are deleted
+        bucket(&[503, 503, 204, 204])
+            .delete("db-1.sql")
+            .await
+            .unwrap();
+        assert_eq!(retries() - before, 2);
+        // not retryable
+        assert!(bucket(&[403, 204]).delete("db-1.sql").await.is_err());
+        // out of attempts
+        assert!(bucket(&[500, 500, 500, 500, 204])
+            .delete("db-1.sql")
+            .await
+            .is_err());
+        assert_eq!(retries() - before, 5);
+    }
+
+    #[tokio::test]
+    async fn test_list_pages() {
+        let page = |keys: &[(&str, &str)], next: Option<&str>| {
+            let contents: String = keys
+                .iter()
+                .map(|(key, modified)| {
+                    format!(
+                        "<Contents><Key>{}</Key><LastModified>{}</LastModified><Size>9</Size></Contents>",
+                        key, modified
+                    )
+                })
+                .collect();
+            let next = match next {
+                Some(token) => format!(
+                    "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+                    token
+                ),
+                None => "<IsTruncated>false</IsTruncated>".to_string(),
+            };
+            MockRequestDispatcher::with_status(200).with_body(&format!(
+                "<ListBucketResult><Name>backups</Name>{}{}</ListBucketResult>",
+                contents, next
+            ))
+        };
+        let dispatcher = MultipleMockRequestDispatcher::new(vec![
+            page(&[("db-1.sql", "2024-04-01T10:00:00.000Z")], Some("page-2")),
+            page(
+                &[
+                    ("db-3.sql", "2024-04-03T10:00:00.000Z"),
+                    (".db-3.sql.sha256", "2024-04-03T10:00:00.000Z"),
+                    ("db-3.sql.sha256", "2024-04-03T10:00:00.000Z"),
+                ],
+                Some("page-3"),
+            ),
+            page(&[("db-2.sql", "2024-04-02T10:00:00.000Z")], None),
+        ]);
+        let keys: Vec<String> = mock_bucket(dispatcher, RetryConfig::default())
+            .list("")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|o| o.key)
+            .collect();
+        // only the hidden checksums are not listed, unlike the files of sha256sum
+        assert_eq!(
+            keys,
+            vec!["db-3.sql", "db-3.sql.sha256", "db-2.sql", "db-1.sql"]
+        );
+        assert_eq!(checksum_key("db/dump-1.sql"), "db/.dump-1.sql.sha256");
+        assert_eq!(checksum_key("dump-1.sql"), ".dump-1.sql.sha256");
+        assert!(is_checksum("db/.dump-1.sql.sha256"));
+        assert!(!is_checksum("db/dump-1.sql.sha256"));
+    }
+
+    #[tokio::test]
+    async fn test_parts_of() {
+        let mut body: ByteReader = Box::pin(std::io::Cursor::new(b"0123456789".to_vec()));
+        let first = read_part(&mut body, 4).await.unwrap();
+        assert_eq!(&first[..], b"0123");
+        let parts: Vec<(i64, Bytes)> = parts_of(first, body, 4, false).try_collect().await.unwrap();
+        assert_eq!(
+            parts,
+            vec![
+                (1, Bytes::from("0123")),
+                (2, Bytes::from("4567")),
+                (3, Bytes::from("89"))
+            ]
+        );
+
+        // the stream fails before the part over the limit is uploaded
+        let mut body: ByteReader = Box::pin(std::io::Cursor::new(vec![0; MAX_PARTS as usize + 1]));
+        let first = read_part(&mut body, 1).await.unwrap();
+        let parts: Vec<anyhow::Result<(i64, Bytes)>> =
+            futures::StreamExt::collect(parts_of(first, body, 1, false)).await;
+        assert_eq!(parts.len(), MAX_PARTS as usize + 1);
+        assert!(parts[..MAX_PARTS as usize].iter().all(|p| p.is_ok()));
+        let err = parts[MAX_PARTS as usize].as_ref().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "upload stream exceeds 10000 parts of S3 upload"
+        );
+
+        // growing parts of the same stream fit
+        let mut body: ByteReader = Box::pin(std::io::Cursor::new(vec![0; MAX_PARTS as usize + 1]));
+        let first = read_part(&mut body, 1).await.unwrap();
+        let parts: Vec<(i64, Bytes)> = parts_of(first, body, 1, true).try_collect().await.unwrap();
+        assert_eq!(parts.len(), 3376);
+    }
+
+    #[tokio::test]
+    async fn test_checksums() {
+        let bucket =
+            |dispatcher: MockRequestDispatcher| mock_bucket(dispatcher, RetryConfig::default());
+        let sha256 = format!("{:x}", Sha256::digest(b"select 1;"));
+
+        let expected = sha256.clone();
+        let dispatcher = MockRequestDispatcher::with_status(200).with_request_checker(
+            move |req: &SignedRequest| {
+                let header = |name: &str| String::from_utf8(req.headers[name][0].clone()).unwrap();
+                assert_eq!(header("content-md5"), "zLW0SBvO05RU3KbYRWAdVA==");
+                assert_eq!(header("x-amz-meta-sha256"), expected);
+            },
+        );
+        let body: ByteReader = Box::pin(std::io::Cursor::new(b"select 1;".to_vec()));
+        bucket(dispatcher)
+            .put("db-1.sql", body, None)
+            .await
+            .unwrap();
+
+        let download = |sha256: &str| {
+            let dispatcher = MockRequestDispatcher::with_status(200)
+                .with_header("x-amz-meta-sha256", sha256)
+                .with_body("select 1;");
+            async move {
+                let mut out = String::new();
+                bucket(dispatcher)
+                    .get("db-1.sql")
+                    .await
+                    .unwrap()
+                    .read_to_string(&mut out)
+                    .await
+                    .map(|_| out)
+            }
+        };
+        assert_eq!(download(&sha256).await.unwrap(), "select 1;");
+        let err = download(&"0".repeat(64)).await.unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+
+        // multipart uploads have the checksum in the separate object
+        let download = |checksum: MockRequestDispatcher| {
+            let dispatcher = MultipleMockRequestDispatcher::new(vec![
+                MockRequestDispatcher::with_status(200).with_body("select 1;"),
+                checksum.with_request_checker(|req: &SignedRequest| {
+                    assert_eq!(req.path, "/backups/.db-1.sql.sha256");
+                }),
+            ]);
+            let bucket = mock_bucket(dispatcher, RetryConfig::default());
+            async move {
+                let mut out = String::new();
+                bucket
+                    .get("db-1.sql")
+                    .await
+                    .unwrap()
+                    .read_to_string(&mut out)
+                    .await
+                    .map(|_| out)
+            }
+        };
+        let checksum = MockRequestDispatcher::with_status(200).with_body(&sha256);
+        assert_eq!(download(checksum).await.unwrap(), "select 1;");
+        let checksum = MockRequestDispatcher::with_status(200).with_body(&"0".repeat(64));
+        assert!(download(checksum).await.is_err());
+        // files uploaded before checksums were introduced
+        let checksum = MockRequestDispatcher::with_status(404);
+        assert_eq!(download(checksum).await.unwrap(), "select 1;");
+    }
+
+    #[tokio::test]
+    async fn test_multipart_checksum() {
+        let contents = vec![7u8; (MIN_PART_SIZE + 1) as usize];
+        let sha256 = format!("{:x}", Sha256::digest(&contents));
+        let upload = |checksum: MockRequestDispatcher, last: MockRequestDispatcher| {
+            let dispatcher = MultipleMockRequestDispatcher::new(vec![
+                MockRequestDispatcher::with_status(200).with_body(
+                    "<InitiateMultipartUploadResult><UploadId>u1</UploadId></InitiateMultipartUploadResult>",
+                ),
+                MockRequestDispatcher::with_status(200),
+                MockRequestDispatcher::with_status(200),
+                checksum.with_request_checker(|req: &SignedRequest| {
+                    assert_eq!(req.method, "PUT");
+                    assert_eq!(req.path, "/backups/.db-1.sql.sha256");
+                }),
+                last,
+            ]);
+            let mut bucket = mock_bucket(dispatcher, RetryConfig::default());
+            bucket.multipart = MultipartConfig {
+                part_size_mb: 5,
+                upload_parallelism: 1,
+            };
+            let body: ByteReader = Box::pin(std::io::Cursor::new(contents.clone()));
+            async move { bucket.put("db-1.sql", body, None).await }
+        };
+
+        // the checksum object is verified by its own checksum
+        let expected = format!("{:x}", Sha256::digest(sha256.as_bytes()));
+        let checksum = MockRequestDispatcher::with_status(200).with_request_checker(
+            move |req: &SignedRequest| {
+                assert_eq!(req.headers["x-amz-meta-sha256"][0], expected.as_bytes());
+            },
+        );
+        let complete =
+            MockRequestDispatcher::with_status(200).with_request_checker(|req: &SignedRequest| {
+                assert_eq!(req.method, "POST");
+                assert_eq!(req.params["uploadId"], Some("u1".to_string()));
+            });
+        assert_eq!(upload(checksum, complete).await.unwrap(), MIN_PART_SIZE + 1);
+
+        // the upload is aborted when its checksum is not stored
+        let abort =
+            MockRequestDispatcher::with_status(204).with_request_checker(|req: &SignedRequest| {
+                assert_eq!(req.method, "DELETE");
+                assert_eq!(req.params["uploadId"], Some("u1".to_string()));
+            });
+        let err = upload(MockRequestDispatcher::with_status(403), abort)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("failed to store checksum"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_range() {
+        let bucket =
+            |dispatcher: MockRequestDispatcher| mock_bucket(dispatcher, RetryConfig::default());
+        let sha256 = format!("{:x}", Sha256::digest(b"select 1;"));
+        let dispatcher = MockRequestDispatcher::with_status(206)
+            .with_header("x-amz-meta-sha256", &sha256)
+            .with_body(" 1;")
+            .with_request_checker(|req: &SignedRequest| {
+                assert_eq!(req.headers["range"][0], b"bytes=6-");
+                assert_eq!(req.headers["if-match"][0], b"\"v1\"");
+            });
+        let prefix: ByteReader = Box::pin(std::io::Cursor::new(b"select".to_vec()));
+        let mut rest = String::new();
+        bucket(dispatcher)
+            .get_range("db-1.sql", "\"v1\"", 6, prefix)
+            .await
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut rest)
+            .await
+            .unwrap();
+        assert_eq!(rest, " 1;");
+
+        // the object was overwritten
+        let dispatcher = MockRequestDispatcher::with_status(412);
+        let prefix: ByteReader = Box::pin(std::io::Cursor::new(b"select".to_vec()));
+        assert!(bucket(dispatcher)
+            .get_range("db-1.sql", "\"v1\"", 6, prefix)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    /// runs against MinIO, e.g. `docker run -p 9000:9000 minio/minio server /data`,
+    /// with the bucket "backups" created, and `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_minio_multipart() {
+        let env = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_string());
+        let region = S3Region::Endpoint(env("S3_TEST_ENDPOINT", "http://localhost:9000"));
+        let credentials = S3Credentials {
+            access_key: Some(env("S3_TEST_ACCESS_KEY", "minioadmin")),
+            secret_access_key: Some(env("S3_TEST_SECRET_KEY", "minioadmin")),
+            ..Default::default()
+        };
+        let bucket = Bucket::new(
+            &credentials,
+            &env("S3_TEST_BUCKET", "backups"),
+            &region,
+            &MultipartConfig::default(),
+            &RetryConfig::default(),
+        )
+        .unwrap();
+
+        let contents = vec![7u8; (40 * MIB) as usize];
+        let body: ByteReader = Box::pin(std::io::Cursor::new(contents.clone()));
+        let size = bucket.put("test/db-1.sql", body, None).await.unwrap();
+        assert_eq!(size, contents.len() as u64);
+        let head = bucket.head("test/db-1.sql").await.unwrap().unwrap();
+        assert_eq!(head.size, contents.len() as i64);
+        // the checksum object is not listed, while the download is verified with it
+        assert_eq!(bucket.list("test/").await.unwrap().len(), 1);
+        let mut out = vec![];
+        let mut body = bucket.get("test/db-1.sql").await.unwrap();
+        body.read_to_end(&mut out).await.unwrap();
+        assert!(out == contents);
+        bucket.delete("test/db-1.sql").await.unwrap();
+        assert!(bucket.list("test/").await.unwrap().is_empty());
     }
 }
@@ -0,0 +1,126 @@
+use anyhow::Context;
+use toml::Value;
+
+/// key of the table, which is replaced with the contents of the file
+const SECRET_FILE: &str = "secret_file";
+
+/// resolves secrets in the config, which is kept without them:
+/// `${NAME}` in any string is replaced with the environment variable (`$${` is kept as `${`),
+/// and `{ secret_file = "/run/secrets/name" }` table with the contents of the file
+pub fn resolve(value: &mut Value) -> anyhow::Result<()> {
+    resolve_at(value, "")
+}
+
+fn resolve_at(value: &mut Value, path: &str) -> anyhow::Result<()> {
+    match value {
+        Value::String(s) => *s = interpolate(s, path)?,
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                resolve_at(item, &format!("{}[{}]", path, index))?;
+            }
+        }
+        Value::Table(table) => match table.get(SECRET_FILE) {
+            Some(file) if table.len() == 1 => {
+                let file = match file {
+                    Value::String(file) => interpolate(file, path)?,
+                    _ => anyhow::bail!("{} of {} is expected to be a string", SECRET_FILE, path),
+                };
+                let contents = std::fs::read_to_string(&file)
+                    .with_context(|| format!("failed to read secret file {} of {}", file, path))?;
+                *value = Value::String(contents.trim_end_matches(['\r', '\n']).to_string());
+            }
+            _ => {
+                for (key, item) in table.iter_mut() {
+                    let path = match path {
+                        "" => key.to_string(),
+                        _ => format!("{}.{}", path, key),
+                    };
+                    resolve_at(item, &path)?;
+                }
+            }
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+/// replaces `${NAME}` with the environment variable, `path` names the value in errors
+fn interpolate(s: &str, path: &str) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .with_context(|| format!("unclosed ${{ in {}", path))?;
+            let name = &after[..end];
+            if name.is_empty() {
+                anyhow::bail!("empty variable name in {}", path);
+            }
+            let value = std::env::var(name).map_err(|_| {
+                anyhow::anyhow!("missing environment variable {} in {}", name, path)
+            })?;
+            out.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let name = format!("BACKUP_TEST_BUCKET_{}", std::process::id());
+        std::env::set_var(&name, "backups");
+        let path =
+            std::env::temp_dir().join(format!("backup-server-secret-{}", std::process::id()));
+        std::fs::write(&path, "s3cr3t\n").unwrap();
+        let mut value: Value = toml::from_str(&format!(
+            r#"
+[realms.media]
+bucket = "${{{name}}}-media"
+secret_access_key = {{ secret_file = "{}" }}
+hosts = ["$5 ${{{name}}}", "$${{literal}}"]
+"#,
+            path.display()
+        ))
+        .unwrap();
+        resolve(&mut value).unwrap();
+        let media = &value["realms"]["media"];
+        assert_eq!(media["bucket"].as_str(), Some("backups-media"));
+        assert_eq!(media["secret_access_key"].as_str(), Some("s3cr3t"));
+        assert_eq!(media["hosts"][0].as_str(), Some("$5 backups"));
+        assert_eq!(media["hosts"][1].as_str(), Some("${literal}"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let error = |contents: &str| {
+            let mut value: Value = toml::from_str(contents).unwrap();
+            resolve(&mut value).unwrap_err().to_string()
+        };
+        assert_eq!(
+            error("[realms.db]\naccess_key = \"${BACKUP_TEST_MISSING}\""),
+            "missing environment variable BACKUP_TEST_MISSING in realms.db.access_key"
+        );
+        assert_eq!(
+            error("[realms.db]\naccess_key = \"${BACKUP_TEST_MISSING\""),
+            "unclosed ${ in realms.db.access_key"
+        );
+        assert!(error("key = { secret_file = \"/nonexistent/secret\" }")
+            .starts_with("failed to read secret file /nonexistent/secret of key"));
+    }
+}
@@ -0,0 +1,262 @@
+use crate::s3::S3Object;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde::Deserialize;
+
+/// how the latest backup of the realm is chosen
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LatestStrategy {
+    /// the most recently modified file in the storage
+    #[default]
+    LastModified,
+    /// the file with the newest timestamp in its name, see `timestamp_format`
+    KeyTimestamp,
+    /// the file with lexicographically greatest key
+    KeyName,
+}
+
+pub fn default_timestamp_format() -> String {
+    "%Y%m%d%H%M%S".to_string()
+}
+
+/// which backup of the realm is pulled
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum PullTarget {
+    /// the latest backup, according to the realm strategy
+    #[default]
+    Latest,
+    /// exact key of the backup
+    Key(String),
+    /// the newest backup made at or before the time
+    At(DateTime<Utc>),
+    /// the Nth most recent backup, 1 is the latest
+    Nth(usize),
+}
+
+impl std::fmt::Display for PullTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Latest => write!(f, "latest"),
+            Self::Key(key) => write!(f, "key {}", key),
+            Self::At(at) => write!(f, "at or before {}", at),
+            Self::Nth(n) => write!(f, "#{} most recent", n),
+        }
+    }
+}
+
+/// orders the list newest first according to the strategy
+pub fn newest_first<'a>(
+    strategy: LatestStrategy,
+    timestamp_format: &str,
+    list: &'a [S3Object],
+) -> Vec<&'a S3Object> {
+    let mut out: Vec<&S3Object> = list.iter().collect();
+    match strategy {
+        LatestStrategy::LastModified => {
+            out.sort_by(|a, b| (b.last_modified, &b.key).cmp(&(a.last_modified, &a.key)))
+        }
+        // files without timestamp are older than any file with it
+        LatestStrategy::KeyTimestamp => out.sort_by_cached_key(|o| {
+            std::cmp::Reverse((key_timestamp(&o.key, timestamp_format), o.key.clone()))
+        }),
+        LatestStrategy::KeyName => out.sort_by(|a, b| b.key.cmp(&a.key)),
+    }
+    out
+}
+
+/// chooses the latest file of the list according to the strategy
+pub fn latest<'a>(
+    strategy: LatestStrategy,
+    timestamp_format: &str,
+    list: &'a [S3Object],
+) -> Option<&'a S3Object> {
+    newest_first(strategy, timestamp_format, list)
+        .into_iter()
+        .next()
+}
+
+/// chooses the file of the list to pull
+pub fn select<'a>(
+    target: &PullTarget,
+    strategy: LatestStrategy,
+    timestamp_format: &str,
+    list: &'a [S3Object],
+) -> Option<&'a S3Object> {
+    match target {
+        PullTarget::Latest => latest(strategy, timestamp_format, list),
+        PullTarget::Key(key) => list.iter().find(|o| o.key == *key),
+        PullTarget::At(at) => {
+            // the time of the backup is taken from its name only when the realm is configured so
+            let time_of = |o: &S3Object| match strategy {
+                LatestStrategy::KeyTimestamp => key_timestamp(&o.key, timestamp_format),
+                _ => Some(o.last_modified),
+            };
+            list.iter()
+                .filter_map(|o| time_of(o).filter(|t| t <= at).map(|t| (t, o)))
+                .max_by(|(a_t, a), (b_t, b)| (a_t, &a.key).cmp(&(b_t, &b.key)))
+                .map(|(_, o)| o)
+        }
+        PullTarget::Nth(n) => newest_first(strategy, timestamp_format, list)
+            .into_iter()
+            .nth(n.checked_sub(1)?),
+    }
+}
+
+/// parses the time of point-in-time restore, in UTC:
+/// RFC 3339, "YYYY-MM-DD HH:MM:SS" or "YYYY-MM-DD", which means the end of the day
+pub fn parse_at(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(ts) = DateTime::parse_from_rfc3339(s) {
+        return Ok(ts.into());
+    }
+    if let Ok(ts) = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S") {
+        return Ok(ts.and_utc());
+    }
+    match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(date) => Ok(date
+            .and_hms_opt(23, 59, 59)
+            .expect("valid time of the day")
+            .and_utc()),
+        Err(_) => Err(format!(
+            "invalid time {}, expected RFC 3339, \"YYYY-MM-DD HH:MM:SS\" or \"YYYY-MM-DD\"",
+            s
+        )),
+    }
+}
+
+/// the first timestamp of the given format found in the file name of the key,
+/// the timestamp is expected to start with a number
+pub fn key_timestamp(key: &str, format: &str) -> Option<DateTime<Utc>> {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    let mut starts = name
+        .char_indices()
+        .filter(|(i, c)| c.is_ascii_digit() && !name[..*i].ends_with(|p: char| p.is_ascii_digit()));
+    starts.find_map(|(i, _)| {
+        let rest = &name[i..];
+        match NaiveDateTime::parse_and_remainder(rest, format) {
+            Ok((ts, _)) => Some(ts.and_utc()),
+            // formats without time of the day
+            Err(_) => NaiveDate::parse_and_remainder(rest, format)
+                .ok()
+                .and_then(|(date, _)| date.and_hms_opt(0, 0, 0))
+                .map(|ts| ts.and_utc()),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(key: &str, modified: &str) -> S3Object {
+        S3Object {
+            key: key.to_string(),
+            last_modified: modified.parse().unwrap(),
+            size: 9,
+        }
+    }
+
+    fn list() -> Vec<S3Object> {
+        // newest first, as backends list them; the last one was re-uploaded later
+        vec![
+            object("db/dump-20240101120000.sql", "2024-04-05T00:00:00Z"),
+            object("db/dump-20240403120000.sql", "2024-04-03T12:00:00Z"),
+            object("db/dump-20240402120000.sql", "2024-04-02T12:00:00Z"),
+            object("db/manual.sql", "2024-04-01T12:00:00Z"),
+        ]
+    }
+
+    #[test]
+    fn test_last_modified() {
+        let list = list();
+        let format = default_timestamp_format();
+        let latest = latest(LatestStrategy::LastModified, &format, &list).unwrap();
+        assert_eq!(latest.key, "db/dump-20240101120000.sql");
+        assert!(super::latest(LatestStrategy::LastModified, &format, &[]).is_none());
+    }
+
+    #[test]
+    fn test_key_timestamp() {
+        let list = list();
+        let latest = latest(
+            LatestStrategy::KeyTimestamp,
+            &default_timestamp_format(),
+            &list,
+        )
+        .unwrap();
+        assert_eq!(latest.key, "db/dump-20240403120000.sql");
+
+        assert_eq!(
+            key_timestamp("2023/db-2024-04-03.sql", "%Y-%m-%d"),
+            Some("2024-04-03T00:00:00Z".parse().unwrap())
+        );
+        assert_eq!(key_timestamp("db/manual.sql", "%Y%m%d%H%M%S"), None);
+    }
+
+    #[test]
+    fn test_select() {
+        let list = list();
+        let format = default_timestamp_format();
+        let by_modified = |target: PullTarget| {
+            select(&target, LatestStrategy::LastModified, &format, &list).map(|o| o.key.as_str())
+        };
+        assert_eq!(
+            by_modified(PullTarget::Key("db/manual.sql".to_string())),
+            Some("db/manual.sql")
+        );
+        assert_eq!(
+            by_modified(PullTarget::Key("db/none.sql".to_string())),
+            None
+        );
+        assert_eq!(
+            by_modified(PullTarget::At(parse_at("2024-04-02").unwrap())),
+            Some("db/dump-20240402120000.sql")
+        );
+        assert_eq!(
+            by_modified(PullTarget::At(parse_at("2024-03-01").unwrap())),
+            None
+        );
+        assert_eq!(
+            by_modified(PullTarget::Nth(2)),
+            Some("db/dump-20240403120000.sql")
+        );
+        assert_eq!(by_modified(PullTarget::Nth(5)), None);
+        assert_eq!(by_modified(PullTarget::Nth(0)), None);
+
+        // point in time of the backup is taken from its name
+        let at = PullTarget::At(parse_at("2024-04-02T23:00:00Z").unwrap());
+        let selected = select(&at, LatestStrategy::KeyTimestamp, &format, &list).unwrap();
+        assert_eq!(selected.key, "db/dump-20240402120000.sql");
+        let nth = select(
+            &PullTarget::Nth(3),
+            LatestStrategy::KeyTimestamp,
+            &format,
+            &list,
+        );
+        assert_eq!(nth.unwrap().key, "db/dump-20240101120000.sql");
+    }
+
+    #[test]
+    fn test_parse_at() {
+        assert_eq!(
+            parse_at("2024-04-03T10:00:00+02:00").unwrap(),
+            "2024-04-03T08:00:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert_eq!(
+            parse_at("2024-04-03 10:00:00").unwrap(),
+            "2024-04-03T10:00:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert_eq!(
+            parse_at("2024-04-03").unwrap(),
+            "2024-04-03T23:59:59Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert!(parse_at("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_key_name() {
+        let list = list();
+        let format = default_timestamp_format();
+        let latest = latest(LatestStrategy::KeyName, &format, &list).unwrap();
+        assert_eq!(latest.key, "db/manual.sql");
+    }
+}
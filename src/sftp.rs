@@ -0,0 +1,371 @@
+use crate::s3::S3Object;
+use crate::storage::{is_partial, partial_name, ByteReader, StorageBackend};
+use anyhow::Context;
+use async_trait::async_trait;
+use russh::client;
+use russh::keys::{PrivateKeyWithHashAlg, PublicKeyOrCertificate};
+use russh_sftp::client::error::Error as SftpError;
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::StatusCode;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tracing::*;
+
+/// how the SSH user is authenticated
+#[derive(Debug)]
+pub enum SshAuth<'a> {
+    /// private key file, with optional passphrase
+    Key {
+        file: &'a str,
+        passphrase: Option<&'a str>,
+    },
+    /// user password
+    Password(&'a str),
+}
+
+/// verifies the server host key
+struct SshHandler {
+    host: String,
+    port: u16,
+    /// known_hosts file with the server key, any key is accepted if missing
+    known_hosts: Option<PathBuf>,
+}
+
+impl client::Handler for SshHandler {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        let key = server_public_key.public_key();
+        match &self.known_hosts {
+            Some(path) => {
+                let known = russh::keys::check_known_hosts_path(&self.host, self.port, &key, path)?;
+                if !known {
+                    error!(
+                        "unknown host key {} of {}, it is not in {}",
+                        key.fingerprint(Default::default()),
+                        self.host,
+                        path.display()
+                    );
+                }
+                Ok(known)
+            }
+            None => {
+                warn!(
+                    "accepting host key {} of {} without verification",
+                    key.fingerprint(Default::default()),
+                    self.host
+                );
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// known_hosts file to verify the server key, `~/.ssh/known_hosts` by default
+fn known_hosts_path(known_hosts: Option<&str>) -> anyhow::Result<PathBuf> {
+    match known_hosts {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => {
+            let home = std::env::var_os("HOME")
+                .context("HOME is not set to find known_hosts, set known_hosts of the realm")?;
+            Ok(PathBuf::from(home).join(".ssh").join("known_hosts"))
+        }
+    }
+}
+
+/// SftpDir keeps realm files in the directory of remote SSH server
+pub struct SftpDir {
+    session: SftpSession,
+    /// keeps SSH connection open while the SFTP session is used
+    _ssh: client::Handle<SshHandler>,
+    pub host: String,
+    pub root: String,
+}
+
+impl std::fmt::Debug for SftpDir {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SftpDir")
+            .field("host", &self.host)
+            .field("root", &self.root)
+            .finish()
+    }
+}
+
+impl SftpDir {
+    /// connects to SSH server and opens SFTP session
+    pub async fn connect(
+        host: &str,
+        port: u16,
+        user: &str,
+        auth: SshAuth<'_>,
+        known_hosts: Option<&str>,
+        accept_any_host_key: bool,
+        root: &str,
+    ) -> anyhow::Result<Self> {
+        tracing::debug!("accessing sftp {}@{}:{}{}", user, host, port, root);
+        let handler = SshHandler {
+            host: host.to_string(),
+            port,
+            known_hosts: match accept_any_host_key {
+                true => None,
+                false => Some(known_hosts_path(known_hosts)?),
+            },
+        };
+        let config = Arc::new(client::Config::default());
+        let mut ssh = client::connect(config, (host, port), handler)
+            .await
+            .with_context(|| format!("failed to connect to {}:{}", host, port))?;
+        let res = match auth {
+            SshAuth::Key { file, passphrase } => {
+                let key = russh::keys::load_secret_key(file, passphrase)
+                    .with_context(|| format!("failed to load ssh key {}", file))?;
+                let hash_alg = ssh.best_supported_rsa_hash().await?.flatten();
+                ssh.authenticate_publickey(
+                    user,
+                    PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg),
+                )
+                .await?
+            }
+            SshAuth::Password(password) => ssh.authenticate_password(user, password).await?,
+        };
+        if !res.success() {
+            anyhow::bail!("ssh authentication failed for {}@{}", user, host);
+        }
+        let channel = ssh.channel_open_session().await?;
+        channel.request_subsystem(true, "sftp").await?;
+        let session = SftpSession::new(channel.into_stream())
+            .await
+            .context("failed to start sftp session")?;
+        Ok(Self {
+            session,
+            _ssh: ssh,
+            host: host.to_string(),
+            root: match root.trim_end_matches('/') {
+                "" => "/".to_string(),
+                root => root.to_string(),
+            },
+        })
+    }
+
+    /// remote path of the file with the given key
+    fn path_of(&self, key: &str) -> String {
+        join_path(&self.root, key)
+    }
+
+    /// creates all missing directories of the path
+    async fn create_dir_all(&self, dir: &str) -> anyhow::Result<()> {
+        let mut current = match dir.starts_with('/') {
+            true => "/".to_string(),
+            false => String::new(),
+        };
+        for part in dir.split('/').filter(|p| !p.is_empty()) {
+            current = join_path(&current, part);
+            if self.session.try_exists(current.clone()).await? {
+                continue;
+            }
+            self.session
+                .create_dir(current.clone())
+                .await
+                .with_context(|| format!("failed to create directory {}", current))?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SftpDir {
+    #[instrument(ret, level = "info")]
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<S3Object>> {
+        let mut out = vec![];
+        // directories to visit, as keys relative to the root
+        let mut dirs = vec![String::new()];
+        while let Some(dir) = dirs.pop() {
+            let entries = self
+                .session
+                .read_dir(self.path_of(&dir))
+                .await
+                .with_context(|| format!("failed to read directory {}", self.path_of(&dir)))?;
+            for entry in entries {
+                let name = entry.file_name();
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let key = join_path(&dir, &name);
+                let meta = entry.metadata();
+                if meta.is_dir() {
+                    dirs.push(key);
+                    continue;
+                }
+                if !key.starts_with(prefix) || is_partial(&key) {
+                    continue;
+                }
+                out.push(S3Object {
+                    key,
+                    last_modified: mtime_of(meta.mtime),
+                    size: meta.len() as i64,
+                });
+            }
+        }
+        // sort out by last modified
+        out.sort_by_key(|o| std::cmp::Reverse(o.last_modified));
+        Ok(out)
+    }
+
+    #[instrument(ret, level = "info", skip(body))]
+    async fn put(
+        &self,
+        filename: &str,
+        mut body: ByteReader,
+        _size: Option<u64>,
+    ) -> anyhow::Result<u64> {
+        let target = self.path_of(filename);
+        let (dir, name) = match target.rsplit_once('/') {
+            Some(("", name)) => ("/", name),
+            Some(parts) => parts,
+            None => ("", target.as_str()),
+        };
+        if !dir.is_empty() {
+            self.create_dir_all(dir).await?;
+        }
+        let tmp = join_path(dir, &partial_name(name));
+        let mut file = self
+            .session
+            .create(tmp.clone())
+            .await
+            .context("failed to create remote file")?;
+        let size = match tokio::io::copy(&mut body, &mut file).await {
+            Ok(size) => size,
+            Err(e) => {
+                // incomplete file is not kept
+                drop(file);
+                let _ = self.session.remove_file(tmp).await;
+                return Err(anyhow::Error::new(e).context("failed to write remote file"));
+            }
+        };
+        file.shutdown().await?;
+        // SFTP v3 servers refuse to rename over an existing file
+        if self.session.try_exists(target.clone()).await? {
+            self.session.remove_file(target.clone()).await?;
+        }
+        self.session
+            .rename(tmp, target)
+            .await
+            .context("failed to rename remote file")?;
+        Ok(size)
+    }
+
+    #[instrument(level = "info")]
+    async fn get(&self, filename: &str) -> anyhow::Result<ByteReader> {
+        let file = self
+            .session
+            .open(self.path_of(filename))
+            .await
+            .context("failed to open remote file")?;
+        Ok(Box::pin(file))
+    }
+
+    #[instrument(ret, level = "warn")]
+    async fn delete(&self, filename: &str) -> anyhow::Result<()> {
+        self.session
+            .remove_file(self.path_of(filename))
+            .await
+            .context("failed to delete remote file")?;
+        Ok(())
+    }
+
+    #[instrument(ret, level = "info")]
+    async fn head(&self, filename: &str) -> anyhow::Result<Option<S3Object>> {
+        let meta = match self.session.metadata(self.path_of(filename)).await {
+            Ok(meta) if !meta.is_dir() => meta,
+            Ok(_) => return Ok(None),
+            Err(SftpError::Status(s)) if s.status_code == StatusCode::NoSuchFile => {
+                return Ok(None)
+            }
+            Err(e) => return Err(anyhow::Error::new(e).context("failed to read metadata")),
+        };
+        Ok(Some(S3Object {
+            key: filename.to_string(),
+            last_modified: mtime_of(meta.mtime),
+            size: meta.len() as i64,
+        }))
+    }
+}
+
+/// joins remote path parts with "/"
+fn join_path(dir: &str, name: &str) -> String {
+    match (dir.is_empty(), name.is_empty()) {
+        (true, _) => name.to_string(),
+        (_, true) => dir.to_string(),
+        _ => format!("{}/{}", dir.trim_end_matches('/'), name),
+    }
+}
+
+fn mtime_of(mtime: Option<u32>) -> chrono::DateTime<chrono::Utc> {
+    mtime
+        .and_then(|t| chrono::DateTime::from_timestamp(t as i64, 0))
+        .unwrap_or_else(chrono::Utc::now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_path() {
+        assert_eq!(join_path("", "db.sql"), "db.sql");
+        assert_eq!(join_path("/backups/", "db.sql"), "/backups/db.sql");
+        assert_eq!(
+            join_path("/backups", "project/db.sql"),
+            "/backups/project/db.sql"
+        );
+        assert_eq!(join_path("/backups", ""), "/backups");
+    }
+
+    #[test]
+    fn test_known_hosts_path() {
+        assert_eq!(
+            known_hosts_path(Some("/etc/ssh/known_hosts")).unwrap(),
+            PathBuf::from("/etc/ssh/known_hosts")
+        );
+        let home = PathBuf::from(std::env::var_os("HOME").unwrap());
+        assert_eq!(
+            known_hosts_path(None).unwrap(),
+            home.join(".ssh/known_hosts")
+        );
+    }
+
+    /// runs against the real SSH server, e.g.
+    /// `docker run -p 2222:22 atmoz/sftp backup:secret:::upload` and
+    /// `SFTP_TEST_PORT=2222 SFTP_TEST_USER=backup SFTP_TEST_PASSWORD=secret SFTP_TEST_PATH=/upload cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_sftp_server() {
+        let env = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_string());
+        let password = env("SFTP_TEST_PASSWORD", "");
+        let dir = SftpDir::connect(
+            &env("SFTP_TEST_HOST", "localhost"),
+            env("SFTP_TEST_PORT", "22").parse().unwrap(),
+            &env("SFTP_TEST_USER", "backup"),
+            SshAuth::Password(&password),
+            None,
+            true,
+            &env("SFTP_TEST_PATH", "/upload"),
+        )
+        .await
+        .unwrap();
+
+        let body: ByteReader = Box::pin(std::io::Cursor::new(b"select 1;".to_vec()));
+        assert_eq!(dir.put("test/db-1.sql", body, None).await.unwrap(), 9);
+        let list = dir.list("test/").await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].size, 9);
+        assert!(dir.head("test/db-1.sql").await.unwrap().is_some());
+        assert!(dir.head("test/db-0.sql").await.unwrap().is_none());
+        dir.delete("test/db-1.sql").await.unwrap();
+        assert!(dir.list("test/").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,410 @@
+use crate::s3::S3Object;
+use async_trait::async_trait;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+/// stream of object contents, used for uploads and downloads
+pub type ByteReader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// StorageBackend is the place where the realm files are kept, one per transport
+#[async_trait]
+pub trait StorageBackend: Send + Sync + std::fmt::Debug {
+    /// lists objects which keys start with the prefix, sorted by last modified, newest first
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<S3Object>>;
+
+    /// uploads the stream under the key, `size` is the length of the stream if known in advance.
+    /// returns number of bytes uploaded
+    async fn put(&self, key: &str, body: ByteReader, size: Option<u64>) -> anyhow::Result<u64>;
+
+    /// opens the stored object for reading
+    async fn get(&self, key: &str) -> anyhow::Result<ByteReader>;
+
+    /// removes the object
+    async fn delete(&self, key: &str) -> anyhow::Result<()>;
+
+    /// object metadata, or None if there is no object with such key
+    async fn head(&self, key: &str) -> anyhow::Result<Option<S3Object>>;
+
+    /// version of the object, which changes when it is overwritten.
+    /// None if the backend can not resume downloads
+    async fn etag(&self, _key: &str) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// opens the object for reading after the `offset` bytes already downloaded as `prefix`,
+    /// which are only used to verify the whole object.
+    /// Returns None if the object is not of the `etag` version anymore
+    async fn get_range(
+        &self,
+        _key: &str,
+        _etag: &str,
+        _offset: u64,
+        _prefix: ByteReader,
+    ) -> anyhow::Result<Option<ByteReader>> {
+        Ok(None)
+    }
+}
+
+/// SyncReader makes the stream `Sync`, as HTTP clients require for request bodies.
+/// The stream is only accessed via `&mut`, so the mutex is never actually locked
+pub struct SyncReader(Mutex<ByteReader>);
+
+impl SyncReader {
+    pub fn new(reader: ByteReader) -> Self {
+        Self(Mutex::new(reader))
+    }
+}
+
+impl AsyncRead for SyncReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let reader = self
+            .get_mut()
+            .0
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner());
+        reader.as_mut().poll_read(cx, buf)
+    }
+}
+
+type CheckFuture = Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>>;
+
+/// CheckedReader fails at the end of the stream if its producer failed,
+/// so the incomplete stream is never taken for the whole one
+struct CheckedReader {
+    inner: ByteReader,
+    check: Option<CheckFuture>,
+}
+
+impl AsyncRead for CheckedReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        match this.inner.as_mut().poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == filled && buf.remaining() > 0 => {}
+            other => return other,
+        }
+        let check = match this.check.as_mut() {
+            Some(check) => check,
+            None => return Poll::Ready(Ok(())),
+        };
+        let res = std::task::ready!(check.as_mut().poll(cx));
+        this.check = None;
+        Poll::Ready(res)
+    }
+}
+
+/// wraps the stream, so its end is only reached if the check succeeds
+pub fn checked_reader(
+    inner: ByteReader,
+    check: impl Future<Output = std::io::Result<()>> + Send + 'static,
+) -> ByteReader {
+    Box::pin(CheckedReader {
+        inner,
+        check: Some(Box::pin(check)),
+    })
+}
+
+/// SharedReader reads the stream, which is drained by its other owner later
+struct SharedReader(Arc<Mutex<ByteReader>>);
+
+impl AsyncRead for SharedReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut inner = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        inner.as_mut().poll_read(cx, buf)
+    }
+}
+
+/// wraps the stream for a decoder, along with the future reading what the decoder left to
+/// the end. Decoders stop at the end of their frame, while the stream may fail only at its end,
+/// e.g. on checksum mismatch
+pub fn drained_reader(
+    inner: ByteReader,
+) -> (
+    ByteReader,
+    impl Future<Output = std::io::Result<()>> + Send + 'static,
+) {
+    let inner = Arc::new(Mutex::new(inner));
+    let mut rest = SharedReader(inner.clone());
+    let drain = async move {
+        let mut buf = vec![0; 64 * 1024];
+        while rest.read(&mut buf).await? > 0 {}
+        Ok(())
+    };
+    (Box::pin(SharedReader(inner)), drain)
+}
+
+/// CountingReader counts the bytes read from the stream
+struct CountingReader {
+    inner: ByteReader,
+    count: Arc<AtomicU64>,
+}
+
+impl AsyncRead for CountingReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let res = this.inner.as_mut().poll_read(cx, buf);
+        let read = buf.filled().len() - filled;
+        this.count.fetch_add(read as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+/// wraps the stream, returning the counter of the bytes read from it
+pub fn counting_reader(inner: ByteReader) -> (ByteReader, Arc<AtomicU64>) {
+    let count = Arc::new(AtomicU64::new(0));
+    let reader = CountingReader {
+        inner,
+        count: count.clone(),
+    };
+    (Box::pin(reader), count)
+}
+
+/// VerifiedReader computes SHA-256 of the stream and fails at its end on mismatch
+struct VerifiedReader {
+    inner: ByteReader,
+    hasher: Sha256,
+    expected: String,
+    verified: bool,
+}
+
+impl AsyncRead for VerifiedReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        std::task::ready!(this.inner.as_mut().poll_read(cx, buf))?;
+        let read = &buf.filled()[filled..];
+        this.hasher.update(read);
+        if read.is_empty() && buf.remaining() > 0 && !this.verified {
+            this.verified = true;
+            let actual = format!("{:x}", this.hasher.finalize_reset());
+            if !actual.eq_ignore_ascii_case(&this.expected) {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "checksum mismatch: expected sha256 {}, got {}",
+                        this.expected, actual
+                    ),
+                )));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// wraps the stream, so its end is only reached if its SHA-256 is the expected hex digest
+pub fn verified_reader(inner: ByteReader, expected_sha256: &str) -> ByteReader {
+    Box::pin(VerifiedReader {
+        inner,
+        hasher: Sha256::new(),
+        expected: expected_sha256.to_string(),
+        verified: false,
+    })
+}
+
+/// same as `verified_reader` for the rest of the stream, after its already read `prefix`
+pub async fn verified_rest(
+    mut prefix: ByteReader,
+    rest: ByteReader,
+    expected_sha256: &str,
+) -> std::io::Result<ByteReader> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match prefix.read(&mut buf).await? {
+            0 => break,
+            read => hasher.update(&buf[..read]),
+        }
+    }
+    Ok(Box::pin(VerifiedReader {
+        inner: rest,
+        hasher,
+        expected: expected_sha256.to_string(),
+        verified: false,
+    }))
+}
+
+/// reads up to `size` bytes of the body, less only at the end of the stream
+pub async fn read_part(body: &mut ByteReader, size: u64) -> std::io::Result<Bytes> {
+    let mut buf = vec![];
+    body.take(size).read_to_end(&mut buf).await?;
+    Ok(buf.into())
+}
+
+/// name of the temporary file, where the upload is written before it is renamed to `name`,
+/// so that the partial copy is never listed as a backup
+pub fn partial_name(name: &str) -> String {
+    format!(".{}.part", name)
+}
+
+/// whether the key belongs to an unfinished upload
+pub fn is_partial(key: &str) -> bool {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    name.starts_with('.') && name.ends_with(".part")
+}
+
+#[cfg(test)]
+pub use memory::MemoryStorage;
+
+#[cfg(test)]
+mod memory {
+    use super::*;
+    use chrono::{DateTime, Utc};
+    use std::collections::BTreeMap as Map;
+
+    #[derive(Debug)]
+    struct MemoryObject {
+        contents: Vec<u8>,
+        last_modified: DateTime<Utc>,
+        /// checksum of the contents on upload, as kept by S3
+        sha256: String,
+    }
+
+    impl MemoryObject {
+        fn stat(&self, key: &str) -> S3Object {
+            S3Object {
+                key: key.to_string(),
+                last_modified: self.last_modified,
+                size: self.contents.len() as i64,
+            }
+        }
+    }
+
+    /// in-memory storage backend for tests
+    #[derive(Debug, Default)]
+    pub struct MemoryStorage {
+        objects: Mutex<Map<String, MemoryObject>>,
+    }
+
+    impl MemoryStorage {
+        /// adds object with the given modification time
+        pub fn insert(&self, key: &str, contents: &[u8], last_modified: DateTime<Utc>) {
+            let object = MemoryObject {
+                contents: contents.to_vec(),
+                last_modified,
+                sha256: format!("{:x}", Sha256::digest(contents)),
+            };
+            self.objects.lock().unwrap().insert(key.to_string(), object);
+        }
+
+        /// changes the contents of the object, keeping its checksum
+        pub fn corrupt(&self, key: &str) {
+            if let Some(object) = self.objects.lock().unwrap().get_mut(key) {
+                object.contents.iter_mut().for_each(|b| *b ^= 1);
+            }
+        }
+
+        /// appends the bytes to the object, keeping its checksum
+        pub fn append(&self, key: &str, bytes: &[u8]) {
+            if let Some(object) = self.objects.lock().unwrap().get_mut(key) {
+                object.contents.extend_from_slice(bytes);
+            }
+        }
+
+        /// keys of all stored objects, in lexicographic order
+        pub fn keys(&self) -> Vec<String> {
+            self.objects.lock().unwrap().keys().cloned().collect()
+        }
+    }
+
+    #[async_trait]
+    impl StorageBackend for MemoryStorage {
+        async fn list(&self, prefix: &str) -> anyhow::Result<Vec<S3Object>> {
+            let mut out: Vec<S3Object> = self
+                .objects
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .map(|(key, object)| object.stat(key))
+                .collect();
+            out.sort_by_key(|o| std::cmp::Reverse(o.last_modified));
+            Ok(out)
+        }
+
+        async fn put(
+            &self,
+            key: &str,
+            mut body: ByteReader,
+            _size: Option<u64>,
+        ) -> anyhow::Result<u64> {
+            let mut contents = vec![];
+            body.read_to_end(&mut contents).await?;
+            let size = contents.len() as u64;
+            self.insert(key, &contents, Utc::now());
+            Ok(size)
+        }
+
+        async fn get(&self, key: &str) -> anyhow::Result<ByteReader> {
+            match self.objects.lock().unwrap().get(key) {
+                Some(object) => Ok(verified_reader(
+                    Box::pin(std::io::Cursor::new(object.contents.clone())),
+                    &object.sha256,
+                )),
+                None => anyhow::bail!("no such key {}", key),
+            }
+        }
+
+        async fn delete(&self, key: &str) -> anyhow::Result<()> {
+            self.objects.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        async fn head(&self, key: &str) -> anyhow::Result<Option<S3Object>> {
+            Ok(self.objects.lock().unwrap().get(key).map(|o| o.stat(key)))
+        }
+
+        async fn etag(&self, key: &str) -> anyhow::Result<Option<String>> {
+            Ok(self
+                .objects
+                .lock()
+                .unwrap()
+                .get(key)
+                .map(|o| o.sha256.clone()))
+        }
+
+        async fn get_range(
+            &self,
+            key: &str,
+            etag: &str,
+            offset: u64,
+            prefix: ByteReader,
+        ) -> anyhow::Result<Option<ByteReader>> {
+            let (rest, sha256) = match self.objects.lock().unwrap().get(key) {
+                Some(object) if object.sha256 == etag => {
+                    let rest = object.contents.get(offset as usize..).unwrap_or_default();
+                    (rest.to_vec(), object.sha256.clone())
+                }
+                _ => return Ok(None),
+            };
+            let rest = Box::pin(std::io::Cursor::new(rest));
+            Ok(Some(verified_rest(prefix, rest, &sha256).await?))
+        }
+    }
+}
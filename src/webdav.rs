@@ -0,0 +1,412 @@
+use crate::s3::S3Object;
+use crate::storage::{is_partial, partial_name, ByteReader, StorageBackend, SyncReader};
+use anyhow::Context;
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Method, StatusCode, Url};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::*;
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/></d:prop>
+</d:propfind>"#;
+
+/// resource from PROPFIND response
+#[derive(Debug, Default, Clone, PartialEq)]
+struct DavEntry {
+    /// decoded path of the resource
+    href: String,
+    is_dir: bool,
+    size: i64,
+    last_modified: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// WebDavDir keeps realm files in the collection of WebDAV server (Nextcloud, ownCloud etc)
+#[derive(Clone)]
+pub struct WebDavDir {
+    client: reqwest::Client,
+    pub url: Url,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+impl std::fmt::Debug for WebDavDir {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebDavDir")
+            .field("url", &self.url.as_str())
+            .finish()
+    }
+}
+
+impl WebDavDir {
+    /// creates new WebDAV collection object
+    pub fn new(url: &str, user: Option<&str>, password: Option<&str>) -> anyhow::Result<Self> {
+        tracing::debug!("accessing webdav {}", url);
+        let mut url = Url::parse(url).context("invalid webdav url")?;
+        // collection URL has to end with "/" for the keys to be resolved inside of it
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        let client = reqwest::Client::builder()
+            .build()
+            .context("Failed to create HTTP client")?;
+        Ok(Self {
+            client,
+            url,
+            user: user.map(str::to_string),
+            password: password.map(str::to_string),
+        })
+    }
+
+    /// URL of the file with the given key
+    fn url_of(&self, key: &str) -> Url {
+        let mut url = self.url.clone();
+        if !key.is_empty() {
+            url.path_segments_mut()
+                .expect("webdav url is a base")
+                .pop_if_empty()
+                .extend(key.split('/'));
+        }
+        url
+    }
+
+    /// URL of the collection with the given key
+    fn collection_url_of(&self, key: &str) -> Url {
+        let mut url = self.url_of(key);
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        url
+    }
+
+    /// key of the resource by its decoded path from PROPFIND response
+    fn key_of(&self, href: &str) -> String {
+        let base = percent_encoding::percent_decode_str(self.url.path()).decode_utf8_lossy();
+        href.strip_prefix(base.as_ref())
+            .unwrap_or(href)
+            .trim_matches('/')
+            .to_string()
+    }
+
+    fn request(&self, method: Method, url: Url) -> reqwest::RequestBuilder {
+        let req = self.client.request(method, url);
+        match &self.user {
+            Some(user) => req.basic_auth(user, self.password.as_ref()),
+            None => req,
+        }
+    }
+
+    /// properties of the resource and its children (with depth "1"),
+    /// None if the resource does not exist
+    async fn propfind(&self, url: Url, depth: &str) -> anyhow::Result<Option<Vec<DavEntry>>> {
+        let res = self
+            .request(Method::from_bytes(b"PROPFIND")?, url)
+            .header("Depth", depth)
+            .header(CONTENT_TYPE, "application/xml")
+            .body(PROPFIND_BODY)
+            .send()
+            .await
+            .context("failed to send PROPFIND")?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let body = res
+            .error_for_status()
+            .context("failed to read collection")?
+            .text()
+            .await?;
+        Ok(Some(parse_multistatus(&body)?))
+    }
+
+    /// creates all missing collections of the key path
+    async fn create_collections(&self, key: &str) -> anyhow::Result<()> {
+        let parts: Vec<&str> = key.split('/').collect();
+        for i in 1..parts.len() {
+            let url = self.collection_url_of(&parts[..i].join("/"));
+            let res = self
+                .request(Method::from_bytes(b"MKCOL")?, url.clone())
+                .send()
+                .await
+                .context("failed to send MKCOL")?;
+            // 405 means the collection already exists
+            if !res.status().is_success() && res.status() != StatusCode::METHOD_NOT_ALLOWED {
+                anyhow::bail!("failed to create collection {}: {}", url, res.status());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for WebDavDir {
+    #[instrument(ret, level = "info")]
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<S3Object>> {
+        let mut out = vec![];
+        // collections to visit, as keys relative to the root
+        let mut dirs = vec![String::new()];
+        while let Some(dir) = dirs.pop() {
+            let entries = self
+                .propfind(self.collection_url_of(&dir), "1")
+                .await?
+                .with_context(|| {
+                    format!("collection {} not found", self.collection_url_of(&dir))
+                })?;
+            for entry in entries {
+                let key = self.key_of(&entry.href);
+                if key == dir {
+                    continue;
+                }
+                if entry.is_dir {
+                    // descend only into collections that can contain the prefix
+                    let sub = format!("{}/", key);
+                    if sub.starts_with(prefix) || prefix.starts_with(&sub) {
+                        dirs.push(key);
+                    }
+                    continue;
+                }
+                if !key.starts_with(prefix) || is_partial(&key) {
+                    continue;
+                }
+                out.push(S3Object {
+                    key,
+                    last_modified: entry.last_modified.unwrap_or_else(chrono::Utc::now),
+                    size: entry.size,
+                });
+            }
+        }
+        // sort out by last modified
+        out.sort_by_key(|o| std::cmp::Reverse(o.last_modified));
+        Ok(out)
+    }
+
+    #[instrument(ret, level = "info", skip(body))]
+    async fn put(
+        &self,
+        filename: &str,
+        body: ByteReader,
+        size: Option<u64>,
+    ) -> anyhow::Result<u64> {
+        self.create_collections(filename).await?;
+        let tmp = match filename.rsplit_once('/') {
+            Some((dir, name)) => format!("{}/{}", dir, partial_name(name)),
+            None => partial_name(filename),
+        };
+        let written = Arc::new(AtomicU64::new(0));
+        let counter = written.clone();
+        let stream = ReaderStream::new(SyncReader::new(body)).inspect_ok(move |chunk| {
+            counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        });
+        let mut req = self
+            .request(Method::PUT, self.url_of(&tmp))
+            .body(reqwest::Body::wrap_stream(stream));
+        if let Some(size) = size {
+            req = req.header(CONTENT_LENGTH, size);
+        }
+        req.send()
+            .await
+            .context("failed to send PUT")?
+            .error_for_status()
+            .context("failed to put file")?;
+        self.request(Method::from_bytes(b"MOVE")?, self.url_of(&tmp))
+            .header("Destination", self.url_of(filename).as_str())
+            .header("Overwrite", "T")
+            .send()
+            .await
+            .context("failed to send MOVE")?
+            .error_for_status()
+            .context("failed to move file")?;
+        Ok(written.load(Ordering::Relaxed))
+    }
+
+    #[instrument(level = "info")]
+    async fn get(&self, filename: &str) -> anyhow::Result<ByteReader> {
+        let res = self
+            .request(Method::GET, self.url_of(filename))
+            .send()
+            .await
+            .context("failed to send GET")?
+            .error_for_status()
+            .context("failed to get file")?;
+        let stream = res.bytes_stream().map_err(std::io::Error::other);
+        Ok(Box::pin(StreamReader::new(stream)))
+    }
+
+    #[instrument(ret, level = "warn")]
+    async fn delete(&self, filename: &str) -> anyhow::Result<()> {
+        self.request(Method::DELETE, self.url_of(filename))
+            .send()
+            .await
+            .context("failed to send DELETE")?
+            .error_for_status()
+            .context("failed to delete file")?;
+        Ok(())
+    }
+
+    #[instrument(ret, level = "info")]
+    async fn head(&self, filename: &str) -> anyhow::Result<Option<S3Object>> {
+        let entries = match self.propfind(self.url_of(filename), "0").await? {
+            Some(entries) => entries,
+            None => return Ok(None),
+        };
+        Ok(entries
+            .into_iter()
+            .find(|entry| !entry.is_dir)
+            .map(|entry| S3Object {
+                key: filename.to_string(),
+                last_modified: entry.last_modified.unwrap_or_else(chrono::Utc::now),
+                size: entry.size,
+            }))
+    }
+}
+
+/// parses multistatus response of PROPFIND request
+fn parse_multistatus(xml: &str) -> anyhow::Result<Vec<DavEntry>> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let mut out = vec![];
+    let mut entry = DavEntry::default();
+    // local name of the element which text is being read
+    let mut current = String::new();
+    loop {
+        match reader.read_event().context("invalid PROPFIND response")? {
+            Event::Start(e) => {
+                current = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                match current.as_str() {
+                    "response" => entry = DavEntry::default(),
+                    "collection" => entry.is_dir = true,
+                    _ => {}
+                }
+            }
+            Event::Empty(e) if e.local_name().as_ref() == b"collection" => entry.is_dir = true,
+            Event::Text(e) => {
+                let text = e.unescape()?;
+                match current.as_str() {
+                    "href" => {
+                        // href can be either absolute URL or absolute path
+                        let path = match Url::parse(&text) {
+                            Ok(url) => url.path().to_string(),
+                            Err(_) => text.to_string(),
+                        };
+                        entry.href = percent_encoding::percent_decode_str(&path)
+                            .decode_utf8_lossy()
+                            .to_string();
+                    }
+                    "getcontentlength" => entry.size = text.parse().unwrap_or_default(),
+                    "getlastmodified" => {
+                        entry.last_modified = chrono::DateTime::parse_from_rfc2822(&text)
+                            .ok()
+                            .map(|d| d.into())
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(e) => {
+                if e.local_name().as_ref() == b"response" {
+                    out.push(std::mem::take(&mut entry));
+                }
+                current.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_multistatus() {
+        let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns" xmlns:oc="http://owncloud.org/ns">
+  <d:response>
+    <d:href>/remote.php/dav/files/backup/db/</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype><d:collection/></d:resourcetype>
+        <d:getlastmodified>Tue, 02 Apr 2024 10:00:00 GMT</d:getlastmodified>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+    <d:propstat>
+      <d:prop><d:getcontentlength/></d:prop>
+      <d:status>HTTP/1.1 404 Not Found</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/backup/db/project%20db-1.sql</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype/>
+        <d:getcontentlength>1024</d:getcontentlength>
+        <d:getlastmodified>Wed, 03 Apr 2024 11:30:00 GMT</d:getlastmodified>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        let entries = parse_multistatus(xml).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].is_dir);
+        assert!(!entries[1].is_dir);
+        assert_eq!(
+            entries[1].href,
+            "/remote.php/dav/files/backup/db/project db-1.sql"
+        );
+        assert_eq!(entries[1].size, 1024);
+        assert_eq!(
+            entries[1].last_modified.unwrap().to_rfc3339(),
+            "2024-04-03T11:30:00+00:00"
+        );
+
+        let dir = WebDavDir::new(
+            "https://cloud.example.com/remote.php/dav/files/backup",
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(dir.key_of(&entries[0].href), "db");
+        assert_eq!(dir.key_of(&entries[1].href), "db/project db-1.sql");
+        assert_eq!(
+            dir.url_of("db/project db-1.sql").as_str(),
+            "https://cloud.example.com/remote.php/dav/files/backup/db/project%20db-1.sql"
+        );
+        assert_eq!(
+            dir.collection_url_of("db").as_str(),
+            "https://cloud.example.com/remote.php/dav/files/backup/db/"
+        );
+    }
+
+    /// runs against the real WebDAV server, e.g.
+    /// `docker run -p 8080:80 -e USERNAME=backup -e PASSWORD=secret bytemark/webdav` and
+    /// `WEBDAV_TEST_URL=http://localhost:8080 cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_webdav_server() {
+        let env = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_string());
+        let dir = WebDavDir::new(
+            &env("WEBDAV_TEST_URL", "http://localhost:8080"),
+            Some(&env("WEBDAV_TEST_USER", "backup")),
+            Some(&env("WEBDAV_TEST_PASSWORD", "secret")),
+        )
+        .unwrap();
+
+        let body: ByteReader = Box::pin(std::io::Cursor::new(b"select 1;".to_vec()));
+        assert_eq!(dir.put("test/db-1.sql", body, Some(9)).await.unwrap(), 9);
+        let list = dir.list("test/").await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].size, 9);
+        assert!(dir.head("test/db-1.sql").await.unwrap().is_some());
+        assert!(dir.head("test/db-0.sql").await.unwrap().is_none());
+        dir.delete("test/db-1.sql").await.unwrap();
+        assert!(dir.list("test/").await.unwrap().is_empty());
+    }
+}